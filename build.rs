@@ -7,7 +7,12 @@
 use std::io;
 
 fn main() -> io::Result<()> {
-    tonic_build::compile_protos("proto/blip.proto")?;
+    // metadata is defined by hand so that it always encodes to the same bytes, which is
+    // required to authenticate the messages that carry it (see src/cluster/proto.rs).
+    tonic_build::configure()
+        .extern_path(".blip.Metadata", "crate::cluster::proto::Metadata")
+        .compile(&["proto/blip.proto"], &["proto"])?;
+    tonic_build::compile_protos("proto/admin.proto")?;
 
     #[cfg(feature = "cache")]
     tonic_build::compile_protos("proto/cache.proto")?;
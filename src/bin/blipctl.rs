@@ -0,0 +1,171 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! A command-line tool for inspecting and operating members of a mesh.
+//!
+//! Members must be served with [Mesh::admin][blip::Mesh::admin] enabled.
+use blip::cluster::admin::{
+    self,
+    proto::{admin_client::AdminClient, InspectReq, LeaveReq, Member, MembersReq, View, WatchReq},
+};
+use prost::Message;
+use std::{env, error::Error, process};
+use tonic::{
+    transport::{Channel, ClientTlsConfig, Endpoint},
+    Request,
+};
+
+const USAGE: &str = "\
+usage: blipctl [--addr <host:port>] [--tls] <command>
+
+options:
+  -a, --addr <host:port>  the member to connect to (default: 127.0.0.1:10000)
+      --tls               connect with tls
+  -h, --help              print this message
+
+environment:
+  BLIP_SECRET             the cluster secret to sign requests with, if the mesh has one
+
+commands:
+  members                 list members of the active configuration, and their metadata
+  conf-id                 print the id of the active configuration
+  watch                   print view-changes as they are accepted
+  inspect                 dump the internal protocol state of the member
+  leave                   ask the member to gracefully leave the mesh";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+struct Args {
+    addr: String,
+    tls: bool,
+    secret: Option<String>,
+    command: String,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = env::args().skip(1);
+    let mut addr = "127.0.0.1:10000".to_owned();
+    let mut tls = false;
+    let mut command = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-a" | "--addr" => addr = args.next().ok_or("missing value for --addr")?,
+            "--tls" => tls = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg).into()),
+            _ if command.is_some() => return Err(format!("unexpected argument: {}", arg).into()),
+            _ => command = Some(arg),
+        }
+    }
+
+    let command = command.ok_or("missing command")?;
+    let secret = env::var("BLIP_SECRET").ok();
+    Ok(Args {
+        addr,
+        tls,
+        secret,
+        command,
+    })
+}
+
+fn request<T: Message>(args: &Args, msg: T) -> Request<T> {
+    let mut req = Request::new(msg);
+    if let Some(secret) = args.secret.as_ref() {
+        admin::sign(secret.as_bytes(), &mut req);
+    }
+    req
+}
+
+async fn connect(args: &Args) -> Result<AdminClient<Channel>> {
+    let scheme = if args.tls { "https" } else { "http" };
+    let mut e = Endpoint::from_shared(format!("{}://{}", scheme, args.addr))?;
+    if args.tls {
+        e = e.tls_config(ClientTlsConfig::new())?;
+    }
+
+    Ok(AdminClient::new(e.connect().await?))
+}
+
+async fn run(args: Args) -> Result<()> {
+    let mut c = connect(&args).await?;
+
+    match args.command.as_str() {
+        "members" => {
+            let view = c.members(request(&args, MembersReq {})).await?.into_inner();
+            view.members.iter().for_each(print_member);
+        }
+
+        "conf-id" => {
+            let view = c.members(request(&args, MembersReq {})).await?.into_inner();
+            println!("{}", view.conf_id);
+        }
+
+        "watch" => {
+            let mut views = c.watch(request(&args, WatchReq {})).await?.into_inner();
+            while let Some(view) = views.message().await? {
+                print_view(&view);
+            }
+        }
+
+        "inspect" => {
+            let state = c.inspect(request(&args, InspectReq {})).await?.into_inner();
+            println!("{:#?}", state);
+        }
+
+        "leave" => {
+            c.leave(request(&args, LeaveReq {})).await?;
+            println!("left: {}", args.addr);
+        }
+
+        cmd => return Err(format!("unknown command: {}", cmd).into()),
+    }
+
+    Ok(())
+}
+
+fn print_member(m: &Member) {
+    let mut meta: Vec<_> = (m.metadata.iter())
+        .map(|(k, v)| format!("{}={}", k, String::from_utf8_lossy(v)))
+        .collect();
+    meta.sort();
+
+    let tls = if m.tls { " tls" } else { "" };
+    println!("{}{} {}", m.addr, tls, meta.join(" "));
+}
+
+fn print_view(view: &View) {
+    let addrs = |ms: &[Member]| ms.iter().map(|m| &*m.addr).collect::<Vec<_>>().join(",");
+
+    println!(
+        "conf_id={} members={} degraded={} joined=[{}] updated=[{}] kicked=[{}]",
+        view.conf_id,
+        view.members.len(),
+        view.degraded,
+        addrs(&view.joined),
+        addrs(&view.updated),
+        addrs(&view.kicked),
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("blipctl: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(args).await {
+        eprintln!("blipctl: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The source of wall-clock time used by the membership protocol.
+//!
+//! This defers to [SystemTime], unless the current thread is running a simulation, in which
+//! case time is measured on the simulation's virtual clock from a fixed epoch, so that it is
+//! reproducible.
+use std::{
+    cell::Cell,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+/// The unix time at which every simulation starts.
+const SIM_EPOCH: u64 = 1_600_000_000;
+
+thread_local! {
+    // const initializers would need rust 1.59.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static STARTED: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Measure time on the current thread from the virtual clock of the runtime it's executing
+/// (starting now), or restore the system clock if `enabled` is false.
+///
+/// Must be called from within the simulation's runtime.
+#[cfg(feature = "sim")]
+pub(crate) fn simulate(enabled: bool) {
+    STARTED.with(|s| s.set(if enabled { Some(Instant::now()) } else { None }));
+}
+
+/// Returns the current time, in seconds since the unix epoch.
+pub(crate) fn unixtime() -> u64 {
+    match STARTED.with(Cell::get) {
+        Some(started) => SIM_EPOCH + started.elapsed().as_secs(),
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("not to be approaching a spacetime singularity")
+            .as_secs(),
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! An operator service that exposes the membership and internal protocol state of a member,
+//! and can ask it to leave the mesh.
+//!
+//! This is served alongside the membership protocol if enabled with [Mesh::admin][admin],
+//! and is mostly useful for debugging view-changes that fail to make progress. Members can
+//! be operated with the generated [AdminClient][client], or the `blipctl` tool (which is
+//! built with the `cli` feature).
+//!
+//! If the mesh is configured with a [cluster_secret][secret], requests to this service must
+//! be signed with the same secret by [sign].
+//!
+//! [admin]: crate::Mesh::admin
+//! [client]: proto::admin_client::AdminClient
+//! [secret]: crate::Mesh::cluster_secret
+use super::{
+    auth::Secret,
+    cut::{Member, MultiNodeCut},
+    proto::{Endpoint, Rank},
+    Cluster, Grpc, GrpcResponse,
+};
+use futures::{channel::mpsc, sink::SinkExt};
+use prost::Message;
+use proto::{
+    admin_server::{Admin, AdminServer},
+    Ballot, InspectReq, Inspection, LeaveReq, LeaveResp, MembersReq, Report, Ring, View, Vote,
+    WatchReq,
+};
+use std::{cmp::Reverse, sync::Arc};
+use tokio::task;
+use tonic::{Request, Response, Status};
+
+/// Protocol buffers definitions for the admin service.
+#[allow(missing_docs)]
+pub mod proto {
+    tonic::include_proto!("blip.admin");
+}
+
+#[crate::async_trait]
+impl Admin for Arc<Cluster> {
+    /// Handle an inspection request by rendering a snapshot of the local state.
+    async fn inspect(&self, _: Request<InspectReq>) -> GrpcResponse<Inspection> {
+        let state = self.state.read().await;
+        let local_node = self.local_node();
+
+        let render = |nodes: &mut dyn Iterator<Item = &Endpoint>| -> Vec<String> {
+            nodes.map(Endpoint::to_string).collect()
+        };
+
+        let (observers, subjects) = if state.nodes.contains(&local_node) {
+            let observers = render(&mut state.nodes.predecessors(&local_node));
+            let subjects = render(&mut state.nodes.successors(&local_node));
+            (observers, subjects)
+        } else {
+            (vec![], vec![])
+        };
+
+        let cd_reports = (state.cd_reports.iter())
+            .map(|(subject, votes)| {
+                let mut votes: Vec<_> = (votes.iter())
+                    .map(|v| Vote {
+                        observer: v.node.to_string(),
+                        ring: v.ring,
+                    })
+                    .collect();
+                votes.sort_by_key(|v| v.ring);
+
+                Report {
+                    subject: subject.to_string(),
+                    votes,
+                }
+            })
+            .collect();
+
+        let mut fpx_ballots: Vec<_> = (state.fpx_ballots.iter())
+            .map(|(nodes, votes)| Ballot {
+                nodes: render(&mut nodes.iter().map(|c| &c.node)),
+                votes: *votes as u64,
+            })
+            .collect();
+        fpx_ballots.sort_by_key(|b| Reverse(b.votes));
+
+        let sorted = |nodes: &mut dyn Iterator<Item = &Endpoint>| {
+            let mut nodes = render(nodes);
+            nodes.sort();
+            nodes
+        };
+
+        let inspection = Inspection {
+            local: local_node.to_string(),
+            conf_id: state.conf_id,
+            rings: (state.nodes.rings())
+                .map(|mut ring| Ring {
+                    nodes: render(&mut ring),
+                })
+                .collect(),
+            observers,
+            subjects,
+            cd_reports,
+            cd_joiners: sorted(&mut state.cd_joiners.keys()),
+            fpx_ballots,
+            px_rnd: rank(&state.px_rnd),
+            px_vrnd: rank(&state.px_vrnd),
+            px_crnd: rank(&state.px_crnd),
+            join_requests: sorted(&mut state.join_requests.keys()),
+        };
+
+        Ok(Response::new(inspection))
+    }
+
+    /// Handle a request to list members by rendering the last accepted view-change.
+    async fn members(&self, _: Request<MembersReq>) -> GrpcResponse<View> {
+        let cut = (self.last_cut().await)
+            .ok_or_else(|| Status::unavailable("no configuration"))?;
+
+        Ok(Response::new(view(&cut)))
+    }
+
+    type WatchStream = mpsc::Receiver<Grpc<View>>;
+
+    /// Handle a watch request by streaming every view-change until the client goes away.
+    async fn watch(&self, _: Request<WatchReq>) -> GrpcResponse<Self::WatchStream> {
+        let mut cuts = self.subscribe();
+        let last = self.last_cut().await;
+        let (mut tx, rx) = mpsc::channel(8);
+
+        task::spawn(async move {
+            let mut conf_id = None;
+
+            if let Some(cut) = last {
+                conf_id = Some(cut.conf_id());
+                if tx.send(Ok(view(&cut))).await.is_err() {
+                    return;
+                }
+            }
+
+            while let Ok(cut) = cuts.recv().await {
+                // the subscription may repeat the cut we started with.
+                if conf_id.take() == Some(cut.conf_id()) {
+                    continue;
+                }
+                if tx.send(Ok(view(&cut))).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(rx))
+    }
+
+    /// Handle a leave request. Resolves once the local node has left the mesh.
+    async fn leave(&self, _: Request<LeaveReq>) -> GrpcResponse<LeaveResp> {
+        Cluster::leave(self).await;
+        Ok(Response::new(LeaveResp {}))
+    }
+}
+
+fn view(cut: &MultiNodeCut) -> View {
+    let members = |ms: &[Member]| -> Vec<_> {
+        (ms.iter())
+            .map(|m| proto::Member {
+                addr: m.addr().to_string(),
+                tls: m.tls_config().is_some(),
+                metadata: m.metadata().clone(),
+            })
+            .collect()
+    };
+
+    View {
+        conf_id: cut.conf_id(),
+        degraded: cut.is_degraded(),
+        members: members(cut.members()),
+        joined: members(cut.joined()),
+        updated: members(cut.updated()),
+        kicked: members(cut.kicked()),
+    }
+}
+
+fn rank(r: &Rank) -> proto::Rank {
+    proto::Rank {
+        round: r.round,
+        node_idx: r.node_idx,
+    }
+}
+
+/// Sign `req` with `secret`, so that it's accepted by members configured with the same
+/// [cluster_secret][crate::Mesh::cluster_secret].
+pub fn sign<T: Message>(secret: &[u8], req: &mut Request<T>) {
+    Secret::new(secret).sign(req);
+}
+
+/// Wraps the admin service of a [Cluster], verifying that requests were signed with the
+/// configured [Secret] (if any) before handling them.
+pub(crate) struct Authenticated(Arc<Cluster>);
+
+macro_rules! verify {
+    ($self:ident, $req:ident) => {
+        if let Some(secret) = $self.0.cfg.secret.as_ref() {
+            secret.verify(&$req)?;
+        }
+    };
+}
+
+#[crate::async_trait]
+impl Admin for Authenticated {
+    async fn inspect(&self, req: Request<InspectReq>) -> GrpcResponse<Inspection> {
+        verify!(self, req);
+        self.0.inspect(req).await
+    }
+
+    async fn members(&self, req: Request<MembersReq>) -> GrpcResponse<View> {
+        verify!(self, req);
+        self.0.members(req).await
+    }
+
+    type WatchStream = mpsc::Receiver<Grpc<View>>;
+
+    async fn watch(&self, req: Request<WatchReq>) -> GrpcResponse<Self::WatchStream> {
+        verify!(self, req);
+        self.0.watch(req).await
+    }
+
+    async fn leave(&self, req: Request<LeaveReq>) -> GrpcResponse<LeaveResp> {
+        verify!(self, req);
+        Admin::leave(&self.0, req).await
+    }
+}
+
+impl Cluster {
+    #[inline]
+    pub(crate) fn into_admin_service(self: Arc<Self>) -> AdminServer<Authenticated> {
+        AdminServer::new(Authenticated(self))
+    }
+}
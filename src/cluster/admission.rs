@@ -0,0 +1,87 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Admission control for nodes joining the mesh.
+use super::proto::{Endpoint, Metadata, NodeId};
+use std::{collections::HashMap, convert::TryFrom, net::SocketAddr, sync::Arc};
+use tonic::transport::Certificate;
+
+/// A node that is requesting to join the mesh.
+#[derive(Clone, Debug)]
+pub struct Joiner {
+    addr: SocketAddr,
+    tls: bool,
+    uuid: u128,
+    meta: HashMap<String, Vec<u8>>,
+    peer_certs: Option<Arc<Vec<Certificate>>>,
+}
+
+impl Joiner {
+    pub(crate) fn new(
+        node: &Endpoint,
+        uuid: &NodeId,
+        meta: &Metadata,
+        peer_certs: Option<Arc<Vec<Certificate>>>,
+    ) -> Self {
+        Self {
+            addr: SocketAddr::try_from(node).expect("joiner endpoint was validated"),
+            tls: node.tls,
+            uuid: uuid.into(),
+            meta: (**meta).clone(),
+            peer_certs,
+        }
+    }
+
+    /// Returns the joiner's listening address.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns true if the joiner expects TLS connections.
+    pub fn uses_tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Returns the unique id the joiner generated for this join attempt.
+    pub fn uuid(&self) -> u128 {
+        self.uuid
+    }
+
+    /// Returns the metadata the joiner will expose to other members.
+    pub fn metadata(&self) -> &HashMap<String, Vec<u8>> {
+        &self.meta
+    }
+
+    /// Returns the certificates the joiner presented when connecting, if the local node is
+    /// serving TLS and the joiner connected with a client certificate.
+    pub fn peer_certs(&self) -> Option<&[Certificate]> {
+        self.peer_certs.as_deref().map(Vec::as_slice)
+    }
+}
+
+/// A hook that decides whether a node may join the mesh.
+///
+/// Each observer of a joining node consults its own hook before raising a join alert, so
+/// every member of the mesh should be configured with an equivalent hook. Rejections are
+/// reported to the joiner as [JoinError::Rejected][rejected], and it will not retry.
+///
+/// This is implemented for closures of the form `Fn(&Joiner) -> Result<(), String>`.
+///
+/// [rejected]: super::JoinError::Rejected
+#[crate::async_trait]
+pub trait Admission: Send + Sync + 'static {
+    /// Returns `Ok(())` if `joiner` may join the mesh, or the reason it was rejected.
+    async fn admit(&self, joiner: &Joiner) -> Result<(), String>;
+}
+
+#[crate::async_trait]
+impl<F> Admission for F
+where F: Fn(&Joiner) -> Result<(), String> + Send + Sync + 'static
+{
+    async fn admit(&self, joiner: &Joiner) -> Result<(), String> {
+        self(joiner)
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Authentication of protocol messages with a secret shared by all members of the mesh.
+//!
+//! Every request sent to another member carries a unique id, and an HMAC-SHA256 tag over
+//! that id and its encoded body. Members reject any request without a valid tag, as well as
+//! any request whose id they have already seen or that was signed too long ago to tell. Only
+//! holders of the secret can produce a valid tag, so hosts outside of the mesh can neither
+//! forge messages nor replay ones they've captured.
+//!
+//! The secret authenticates that a message was sent by some holder of it, but not by which
+//! one: any member can sign a message on behalf of any sender. See the limitations of
+//! [Mesh::cluster_secret][crate::Mesh::cluster_secret].
+use super::Grpc;
+use crate::collections::{EventFilter, EventId};
+use prost::Message;
+use ring::hmac;
+use std::{
+    convert::TryInto,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tonic::{metadata::MetadataValue, Request, Status};
+
+/// The metadata key that holds the tag of a request.
+const MAC_KEY: &str = "blip-mac-bin";
+
+/// The metadata key that holds the id of a request.
+const ID_KEY: &str = "blip-id-bin";
+
+/// How long the ids of verified requests are remembered. Requests signed longer ago than
+/// this are rejected, so members' clocks must agree to well within it.
+const REPLAY_WINDOW: Duration = Duration::from_secs(300);
+
+/// A secret shared by all members of the mesh.
+#[derive(Clone)]
+pub(crate) struct Secret {
+    key: hmac::Key,
+    // ids of recently verified requests, shared by all clones.
+    verified: Arc<Mutex<EventFilter>>,
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Secret {
+    pub(crate) fn new(secret: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            verified: Arc::new(Mutex::new(EventFilter::new(REPLAY_WINDOW))),
+        }
+    }
+
+    /// Attach a new id to the metadata of `req`, along with a tag over it and the body.
+    pub(crate) fn sign<T: Message>(&self, req: &mut Request<T>) {
+        self.sign_as(EventId::generate(), req);
+    }
+
+    fn sign_as<T: Message>(&self, id: EventId, req: &mut Request<T>) {
+        let id = encode_id(id);
+        let tag = hmac::sign(&self.key, &mac_input(&id, req.get_ref()));
+
+        let meta = req.metadata_mut();
+        meta.insert_bin(ID_KEY, MetadataValue::from_bytes(&id));
+        meta.insert_bin(MAC_KEY, MetadataValue::from_bytes(tag.as_ref()));
+    }
+
+    /// Verify that `req` carries a valid tag over its id and body, and that its id hasn't
+    /// been seen before.
+    pub(crate) fn verify<T: Message>(&self, req: &Request<T>) -> Grpc<()> {
+        let bin = |key| (req.metadata().get_bin(key)).and_then(|v| v.to_bytes().ok());
+
+        let tag = bin(MAC_KEY)
+            .ok_or_else(|| Status::unauthenticated("missing message authentication code"))?;
+        let id = (bin(ID_KEY))
+            .filter(|id| id.len() == 16)
+            .ok_or_else(|| Status::unauthenticated("missing message id"))?;
+
+        hmac::verify(&self.key, &mac_input(&id, req.get_ref()), &tag)
+            .map_err(|_| Status::unauthenticated("invalid message authentication code"))?;
+
+        if !self.verified.lock().unwrap().insert(decode_id(&id)) {
+            return Err(Status::unauthenticated("replayed or expired message"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the bytes that the tag of a message with `id` is computed over.
+fn mac_input<T: Message>(id: &[u8], msg: &T) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(id.len() + msg.encoded_len());
+    buf.extend_from_slice(id);
+    msg.encode(&mut buf).expect("buffer has sufficient capacity");
+    buf
+}
+
+fn encode_id(id: EventId) -> [u8; 16] {
+    let mut buf = [0; 16];
+    buf[..8].copy_from_slice(&id.timestamp().to_be_bytes());
+    buf[8..].copy_from_slice(&id.unique().to_be_bytes());
+    buf
+}
+
+fn decode_id(buf: &[u8]) -> EventId {
+    let unix = u64::from_be_bytes(buf[..8].try_into().unwrap());
+    let uniq = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    EventId::new(unix, uniq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::proto::{Metadata, UpdateMetadataReq};
+    use std::net::SocketAddr;
+    use tonic::Code;
+
+    fn update_req() -> UpdateMetadataReq {
+        let mut meta = Metadata::default();
+        meta.extend((0..8u8).map(|i| (i.to_string(), vec![i])));
+
+        UpdateMetadataReq {
+            sender: SocketAddr::from(([127, 0, 0, 1], 10000)).into(),
+            conf_id: 1,
+            meta,
+        }
+    }
+
+    #[test]
+    fn signed_requests_are_verified() {
+        let secret = Secret::new(b"hunter2");
+        let mut req = Request::new(update_req());
+        secret.sign(&mut req);
+
+        assert!(secret.verify(&req).is_ok());
+    }
+
+    #[test]
+    fn forged_requests_are_rejected() {
+        let secret = Secret::new(b"hunter2");
+
+        let unsigned = Request::new(update_req());
+        let e = secret.verify(&unsigned).unwrap_err();
+        assert_eq!(Code::Unauthenticated, e.code());
+
+        let mut wrong_secret = Request::new(update_req());
+        Secret::new(b"hunter3").sign(&mut wrong_secret);
+        let e = secret.verify(&wrong_secret).unwrap_err();
+        assert_eq!(Code::Unauthenticated, e.code());
+
+        let mut tampered = Request::new(update_req());
+        secret.sign(&mut tampered);
+        tampered.get_mut().conf_id += 1;
+        let e = secret.verify(&tampered).unwrap_err();
+        assert_eq!(Code::Unauthenticated, e.code());
+    }
+
+    #[test]
+    fn replayed_requests_are_rejected() {
+        let secret = Secret::new(b"hunter2");
+        let mut req = Request::new(update_req());
+        secret.sign(&mut req);
+
+        assert!(secret.verify(&req).is_ok());
+        let e = secret.verify(&req).unwrap_err();
+        assert_eq!(Code::Unauthenticated, e.code());
+
+        let mut expired = Request::new(update_req());
+        secret.sign_as(EventId::new(0, 1), &mut expired);
+        let e = secret.verify(&expired).unwrap_err();
+        assert_eq!(Code::Unauthenticated, e.code());
+    }
+
+    #[test]
+    fn requests_are_verified_regardless_of_metadata_order() {
+        let secret = Secret::new(b"hunter2");
+        let mut req = Request::new(update_req());
+        secret.sign(&mut req);
+
+        // a new map (most likely) iterates over the same keys in a different order.
+        let meta = &mut req.get_mut().meta;
+        meta.keys = meta.keys.drain().collect();
+
+        assert!(secret.verify(&req).is_ok());
+    }
+}
@@ -4,81 +4,121 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-//! Logic for cluster initialization and partition detection.
+//! Logic for cluster initialization, metadata updates, departure, and partition detection.
 use super::{
-    cut::{self, MultiNodeCut, Subscription},
+    cut::{MultiNodeCut, Subscription},
     proto::{
-        membership_client::MembershipClient, Endpoint, EndpointError, Join, JoinReq, JoinResp,
-        NodeId, NodeMetadata, PreJoinReq, PreJoinResp,
+        DiscoverReq, DiscoverResp, Endpoint, FetchConfigReq, Join, JoinReq, JoinResp, LeaveReq,
+        Metadata, NodeId, NodeMetadata, PreJoinReq, PreJoinResp, Snapshot, UpdateMetadataReq,
     },
-    Cluster, State,
+    Cluster, Grpc, State,
 };
+use crate::{overlay, rng::local_rng};
 use futures::{
     future::TryFutureExt,
     stream::{FuturesUnordered, StreamExt},
 };
 use log::{info, warn};
-use std::{borrow::Cow, cmp, sync::Arc, time::Duration};
+use rand::seq::SliceRandom;
+use std::{
+    borrow::Cow,
+    cmp,
+    collections::HashMap,
+    future::Future,
+    sync::{atomic::Ordering::SeqCst, Arc},
+    time::Duration,
+};
 use thiserror::Error;
-use tokio::time::{error::Elapsed, sleep, timeout};
-use tonic::transport;
+use tokio::time::{error::Elapsed, sleep, timeout, Instant};
+use tonic::{Code, Request, Status};
 
+/// An error encountered while joining a mesh.
 #[derive(Debug, Error)]
-enum JoinError {
+pub enum JoinError {
+    /// The join did not complete in time.
     #[error("timed out: {}", .0)]
     TimedOut(#[from] Elapsed),
 
-    #[error("endpoint resolution failed: {}", .0)]
-    Resolution(#[from] EndpointError),
-
+    /// The seed node could not be contacted, or refused the pre-join request.
     #[error("phase 1 failed: {}", .0)]
-    Phase1(GrpcError),
+    Phase1(Status),
 
+    /// The observers could not be contacted, or refused the join request.
     #[error("phase 2 failed: {}", .0)]
-    Phase2(GrpcError),
+    Phase2(Status),
 
+    /// The seed node didn't provide any observers to contact.
     #[error("phase 2 failed: no observers")]
     NoObservers,
-}
-
-#[derive(Debug, Error)]
-enum GrpcError {
-    #[error("connection failed: {}", .0)]
-    Connect(#[from] transport::Error),
 
-    #[error("call failed: {}", .0)]
-    Call(#[from] tonic::Status),
+    /// The join was rejected by an [Admission][super::admission::Admission] hook. Joins
+    /// will not be retried after this is encountered.
+    #[error("rejected: {}", .0)]
+    Rejected(String),
 }
 
 impl Cluster {
     /// Handle network partitions where the local member is ejected from the cluster by rejoining
     /// through random healthy members (from the last-seen cut), or bootstrapping if this node is
     /// a seed node.
-    pub(crate) async fn handle_parts(self: Arc<Self>, mut cuts: Subscription) -> cut::Result {
-        self.initialize().await;
+    ///
+    /// Resolves with an error if the local member is refused entry to the cluster.
+    pub(crate) async fn handle_parts(self: Arc<Self>, mut cuts: Subscription) -> overlay::Result {
+        self.initialize().await?;
 
         loop {
             let cut = cuts.recv().await?;
 
-            if !cut.is_degraded() {
+            if !cut.is_degraded() || self.leaving.load(SeqCst) || self.merging.load(SeqCst) {
                 continue;
             }
 
             if cut.members().is_empty() {
-                self.initialize().await;
+                self.initialize().await?;
                 continue;
             }
 
             self.join_via_backoff(|| Cow::Owned(cut.random_member().into()))
-                .await;
+                .await?;
         }
     }
 
-    /// Initialize as if we just started up by attempting to join a seed node, or becoming
-    /// a single node bootstrap cluster.
-    async fn initialize(&self) {
-        if let Some(seed) = self.cfg.seed.as_ref() {
-            self.join_via_backoff(|| Cow::Borrowed(seed)).await;
+    /// Initialize as if we just started up by attempting to join via the configured seed
+    /// nodes (and members of the persisted configuration), or becoming a single node
+    /// bootstrap cluster.
+    ///
+    /// If static recovery is enabled, the persisted configuration is reinstated instead.
+    async fn initialize(&self) -> Result<(), JoinError> {
+        let snapshot = self.load_snapshot().await;
+
+        if let Some(snapshot) = snapshot.as_ref().filter(|_| self.cfg.static_recovery) {
+            let mut state = self.state.write().await;
+
+            if self.restore(&mut state, snapshot) {
+                return Ok(());
+            }
+            warn!("static recovery failed: not in persisted configuration");
+        }
+
+        let local_node = self.local_node();
+        let mut seeds: Vec<_> = self.cfg.seeds.iter().collect();
+
+        for NodeMetadata { node, .. } in snapshot.iter().flat_map(|s| &s.nodes) {
+            if *node != local_node && !seeds.contains(&node) {
+                seeds.push(node);
+            }
+        }
+
+        if !seeds.is_empty() {
+            if self.cfg.shuffle_seeds {
+                seeds.shuffle(&mut local_rng());
+            }
+
+            let mut seeds = seeds.into_iter().cycle();
+            self.join_via_backoff(|| Cow::Borrowed(seeds.next().unwrap()))
+                .await
+        } else if let Some(expect) = self.cfg.bootstrap_expect {
+            self.bootstrap_expect(expect).await
         } else {
             let mut state = self.state.write().await;
 
@@ -86,12 +126,107 @@ impl Cluster {
             state.clear_membership();
 
             self.bootstrap(&mut state);
+            Ok(())
+        }
+    }
+
+    /// Wait until `expect` candidates (including the local node) among the configured
+    /// bootstrap peers are reachable, and every one of them has discovered the same set of
+    /// candidates. The candidate with the lowest address then bootstraps a new cluster, which
+    /// the rest join once they discover it.
+    ///
+    /// If any peer is already a member of a configuration, it is joined instead.
+    async fn bootstrap_expect(&self, expect: usize) -> Result<(), JoinError> {
+        let t = &self.cfg.timings;
+        let mut retry_backoff = t.join_retry_min;
+
+        loop {
+            let mut candidates = vec![self.local_node()];
+            let mut views = Vec::new();
+            let mut members = Vec::new();
+
+            for resp in self.discover(&self.cfg.bootstrap_peers).await {
+                match resp.members {
+                    0 => {
+                        candidates.push(resp.sender);
+                        views.push(resp.candidates);
+                    }
+                    _ => members.push(resp.sender),
+                }
+            }
+
+            if !members.is_empty() {
+                let mut seeds = members.iter().cycle();
+                return self
+                    .join_via_backoff(|| Cow::Borrowed(seeds.next().unwrap()))
+                    .await;
+            }
+
+            candidates.sort();
+            candidates.dedup();
+
+            let agreed = views.iter().all(|v| *v == candidates);
+            let mut state = self.state.write().await;
+            state.bootstrap_candidates = candidates.clone();
+
+            // non-bootstrappers keep waiting, and join the bootstrapper once it's a member.
+            if candidates.len() >= expect && agreed && candidates[0] == self.local_node() {
+                state.clear_consensus();
+                state.clear_membership();
+                state.bootstrap_candidates.clear();
+
+                self.bootstrap(&mut state);
+                return Ok(());
+            }
+            drop(state);
+
+            info!("waiting for bootstrap peers: {}/{}", candidates.len(), expect);
+
+            sleep(retry_backoff).await;
+            retry_backoff = cmp::min(retry_backoff * 2, t.join_retry_max);
+        }
+    }
+
+    /// Concurrently ask each of `peers` (other than the local node) which configuration
+    /// they're a member of. Peers that don't respond within the fault detection timeout are
+    /// omitted.
+    pub(super) async fn discover(&self, peers: &[Endpoint]) -> Vec<DiscoverResp> {
+        let local_node = self.local_node();
+        let req = DiscoverReq {
+            sender: local_node.clone(),
+            cluster_name: self.cfg.cluster_name.clone(),
+        };
+
+        (peers.iter())
+            .filter(|peer| **peer != local_node)
+            .map(|peer| timeout(self.cfg.fd_timeout, self.request_discover(req.clone(), peer)))
+            .collect::<FuturesUnordered<_>>()
+            .filter_map(|r| async { r.ok()?.ok() })
+            .collect()
+            .await
+    }
+
+    /// Reinstate a persisted configuration without contacting any other members. Returns
+    /// false if the local node wasn't a member of it.
+    fn restore(&self, state: &mut State, snapshot: &Snapshot) -> bool {
+        let local_node = self.local_node();
+
+        if !snapshot.nodes.iter().any(|n| n.node == local_node)
+            || !snapshot.uuids.contains(&snapshot.uuid)
+        {
+            return false;
         }
+
+        state.uuid = snapshot.uuid.clone();
+        self.install_config(state, snapshot.nodes.clone(), snapshot.uuids.clone(), false);
+
+        info!("restored: conf_id={}", state.conf_id);
+        true
     }
 
     /// Boostrap a new cluster. This will not reset any membership state, and should only
     /// be called with a blank [State].
-    fn bootstrap(&self, state: &mut State) {
+    pub(super) fn bootstrap(&self, state: &mut State) {
         // NOTE(invariant): must only be called with completely cleared state
         assert!(state.nodes.is_empty());
         assert!(state.uuids.is_empty());
@@ -100,10 +235,10 @@ impl Cluster {
 
         let node = Endpoint::from(self.addr).tls(self.cfg.server_tls);
         let uuid = state.uuid.clone();
-        let meta = self.cfg.meta.clone();
+        let meta = state.meta.clone();
 
         let members: Arc<[_]> = vec![self
-            .resolve_member_meta(self.cfg.meta.clone(), &node)
+            .resolve_member_meta(state.meta.clone(), &node)
             .unwrap()]
         .into();
 
@@ -112,10 +247,13 @@ impl Cluster {
         let cut = MultiNodeCut {
             skipped: 0,
             local_addr: self.addr,
+            cluster_name: state.cluster_name.clone(),
             conf_id: state.rehash_config(),
             degraded: false,
+            merged: false,
             members: members.clone(),
             joined: members,
+            updated: vec![].into(),
             kicked: vec![].into(),
         };
 
@@ -127,41 +265,71 @@ impl Cluster {
 
     /// Join a cluster using the provided function to generate a seed on each attempt.
     ///
-    /// Uses exponential backoff if join failures are encountered.
-    async fn join_via_backoff<'a, F: FnMut() -> Cow<'a, Endpoint>>(&self, mut seed: F) {
-        const RETRY_MAX: Duration = Duration::from_secs(4);
-        const JOIN_MAX: Duration = Duration::from_secs(15);
-
-        let mut retry_backoff = Duration::from_millis(200);
-        let mut join_backoff = Duration::from_secs(5);
-
-        while let Err(e) = self.join_via(&seed(), join_backoff).await {
+    /// Uses exponential backoff if join failures are encountered, unless the join was
+    /// rejected outright.
+    pub(super) async fn join_via_backoff<'a, F>(&self, mut seed: F) -> Result<(), JoinError>
+    where F: FnMut() -> Cow<'a, Endpoint> {
+        let t = &self.cfg.timings;
+        let mut retry_backoff = t.join_retry_min;
+        let mut join_backoff = t.join_timeout_min;
+        let start = Instant::now();
+
+        while let Err(e) = self.join_via(&seed(), join_backoff, false).await {
+            self.cfg.metrics.record_join_failure(&e);
+            if let JoinError::Rejected(_) = e {
+                return Err(e);
+            }
             warn!("join failed: {}", e);
 
             sleep(retry_backoff).await;
-            retry_backoff = cmp::min(retry_backoff * 2, RETRY_MAX);
-            join_backoff = cmp::min(join_backoff + (join_backoff / 2), JOIN_MAX);
+            retry_backoff = cmp::min(retry_backoff * 2, t.join_retry_max);
+            join_backoff = cmp::min(join_backoff + (join_backoff / 2), t.join_timeout_max);
         }
-    }
 
-    /// Attempt to join a cluster via the provided seed node.
-    async fn join_via(&self, seed: &Endpoint, max_wait: Duration) -> Result<(), JoinError> {
-        let mut state = self.state.write().await;
-
-        state.uuid = NodeId::generate();
+        self.cfg.metrics.join_latency().observe(start.elapsed());
+        Ok(())
+    }
 
+    /// Attempt to join a cluster via the provided seed node. If `merged` is set, the join is
+    /// reported as a merge of the local node's previous configuration into that cluster.
+    pub(super) async fn join_via(
+        &self,
+        seed: &Endpoint,
+        max_wait: Duration,
+        merged: bool,
+    ) -> Result<(), JoinError> {
+        let uuid = NodeId::generate();
+        let meta = self.state.read().await.meta.clone();
+
+        // the state lock isn't held while joining, as the local node may still be serving
+        // requests as a member of its previous configuration.
         info!("requesting join: timeout={:?}", max_wait);
         let JoinResp { nodes, uuids, .. } =
-            timeout(max_wait, self.request_join(&state, seed)).await??;
+            timeout(max_wait, self.request_join(&uuid, &meta, seed)).await??;
+
+        let mut state = self.state.write().await;
+        state.uuid = uuid;
+        self.install_config(&mut state, nodes, uuids, merged);
+
+        info!("joined: conf_id={}", state.conf_id);
+        Ok(())
+    }
 
+    /// Replace the active configuration with one consisting of `nodes` and `uuids`.
+    fn install_config(
+        &self,
+        state: &mut State,
+        nodes: Vec<NodeMetadata>,
+        uuids: Vec<NodeId>,
+        merged: bool,
+    ) {
         state.clear_consensus();
         state.clear_membership();
 
         let mut joined = Vec::with_capacity(nodes.len());
         for NodeMetadata { node, meta } in nodes {
             joined.push(self.resolve_member_meta(meta.clone(), &node).unwrap());
-            assert!(state.nodes.insert(node.clone()));
-            assert!(state.metadata.insert(node, meta).is_none());
+            state.insert_node(node, meta);
         }
         for uuid in uuids {
             assert!(state.uuids.insert(uuid));
@@ -170,7 +338,7 @@ impl Cluster {
         joined.sort_by_key(|m| m.addr());
 
         let mut members: Vec<_> = (state.nodes.iter())
-            .map(|node| self.resolve_member(&state, node).unwrap())
+            .map(|node| self.resolve_member(state, node).unwrap())
             .collect();
 
         members.sort_by_key(|m| m.addr());
@@ -178,26 +346,117 @@ impl Cluster {
         let cut = MultiNodeCut {
             skipped: 0,
             local_addr: self.addr,
+            cluster_name: state.cluster_name.clone(),
             degraded: !state.nodes.contains(&self.local_node()),
+            merged,
             conf_id: state.rehash_config(),
             members: members.into(),
             joined: joined.into(),
+            updated: vec![].into(),
             kicked: vec![].into(),
         };
 
         state.last_cut = Some(cut.clone());
         self.propagate_cut(cut);
+    }
 
-        info!("joined: conf_id={}", state.conf_id);
+    /// Replace the `stale` active configuration with the one `peer` is a member of, after
+    /// missing the broadcast of a view-change to it.
+    ///
+    /// Unlike a rejoin, the local node remains a member throughout, and the resulting cut
+    /// only reports the differences between the two configurations. Nothing is replaced if
+    /// the local node isn't a member of the fetched configuration, or if a view-change was
+    /// accepted in the meantime.
+    pub(crate) async fn catch_up(&self, peer: &Endpoint, stale: u64) -> Grpc<()> {
+        let req = FetchConfigReq {
+            sender: self.local_node(),
+            cluster_name: self.cfg.cluster_name.clone(),
+        };
+
+        let c = self.connect(peer).await?;
+        let JoinResp { nodes, uuids, .. } =
+            (timeout(self.cfg.fd_timeout, c.fetch_config(Request::new(req))).await)
+                .map_err(|e| Status::deadline_exceeded(e.to_string()))??
+                .into_inner();
+
+        let local_node = self.local_node();
+        if !nodes.iter().any(|n| n.node == local_node) {
+            return Err(Status::failed_precondition("not in fetched configuration"));
+        }
+
+        let mut state = self.state.write().await;
+        if state.conf_id != stale {
+            return Ok(());
+        }
+
+        let mut joined = Vec::new();
+        let mut updated = Vec::new();
+        for NodeMetadata { node, meta } in nodes.iter() {
+            match state.metadata.get(node) {
+                Some(prev) if prev == meta => {}
+                Some(_) => updated.push(self.resolve_member_meta(meta.clone(), node).unwrap()),
+                None => joined.push(self.resolve_member_meta(meta.clone(), node).unwrap()),
+            }
+        }
+
+        let mut kicked: Vec<_> = (state.nodes.iter())
+            .filter(|node| !nodes.iter().any(|n| n.node == **node))
+            .map(|node| self.resolve_member(&state, node).unwrap())
+            .collect();
+
+        state.clear_consensus();
+        state.clear_membership();
+
+        for NodeMetadata { node, meta } in nodes {
+            state.insert_node(node, meta);
+        }
+        for uuid in uuids {
+            assert!(state.uuids.insert(uuid));
+        }
+
+        joined.sort_by_key(|m| m.addr());
+        updated.sort_by_key(|m| m.addr());
+        kicked.sort_by_key(|m| m.addr());
+
+        let mut members: Vec<_> = (state.nodes.iter())
+            .map(|node| self.resolve_member(&state, node).unwrap())
+            .collect();
+
+        members.sort_by_key(|m| m.addr());
+
+        let cut = MultiNodeCut {
+            skipped: 0,
+            local_addr: self.addr,
+            cluster_name: state.cluster_name.clone(),
+            degraded: false,
+            merged: false,
+            conf_id: state.rehash_config(),
+            members: members.into(),
+            joined: joined.into(),
+            updated: updated.into(),
+            kicked: kicked.into(),
+        };
+
+        state.last_cut = Some(cut.clone());
+        self.propagate_cut(cut);
+
+        info!("caught up: conf_id={}", state.conf_id);
         Ok(())
     }
 
     /// Request to join the provided seed node. Returns `Ok(_)` if both phases of the join
     /// protocol completed successfully.
-    async fn request_join(&self, state: &State, seed: &Endpoint) -> Result<JoinResp, JoinError> {
+    async fn request_join(
+        &self,
+        uuid: &NodeId,
+        meta: &Metadata,
+        seed: &Endpoint,
+    ) -> Result<JoinResp, JoinError> {
         let p1j_req = PreJoinReq {
             sender: self.local_node(),
-            uuid: state.uuid.clone(),
+            uuid: uuid.clone(),
+            cluster_name: self.cfg.cluster_name.clone(),
+            cd_config: self.cd_config(),
         };
 
         let r1 = self.join_phase1(p1j_req, seed).await?;
@@ -207,9 +466,10 @@ impl Cluster {
         let p2j_req = |ring| JoinReq {
             sender: self.local_node(),
             ring: ring as u64,
-            uuid: state.uuid.clone(),
+            uuid: uuid.clone(),
             conf_id,
-            meta: self.cfg.meta.clone(),
+            meta: meta.clone(),
+            cluster_name: self.cfg.cluster_name.clone(),
         };
 
         let mut joins = (r1.contact.into_iter())
@@ -222,6 +482,7 @@ impl Cluster {
         while let Some(resp) = joins.next().await {
             match resp {
                 Ok(resp) => return Ok(resp),
+                Err(err @ JoinError::Rejected(_)) => return Err(err),
                 Err(err) => e = Some(err),
             }
         }
@@ -231,27 +492,171 @@ impl Cluster {
 
     /// Initiate phase 1 of the join protocol via the provided endpoint.
     async fn join_phase1(&self, req: PreJoinReq, via: &Endpoint) -> Result<PreJoinResp, JoinError> {
-        let seed = self.resolve_endpoint(via)?;
-
-        let mut c = MembershipClient::connect(seed)
-            .map_err(|e| JoinError::Phase1(e.into()))
-            .await?;
+        let c = self.connect(via).map_err(JoinError::Phase1).await?;
 
-        (c.pre_join(req).map_ok(|r| r.into_inner()))
-            .map_err(|e| JoinError::Phase1(e.into()))
+        (c.pre_join(Request::new(req)).map_ok(|r| r.into_inner()))
+            .map_err(JoinError::Phase1)
             .await
     }
 
     /// Initiate phase 2 of the join protocol with the provided `observer`.
     async fn join_phase2(&self, req: JoinReq, observer: Endpoint) -> Result<JoinResp, JoinError> {
-        let observer = self.resolve_endpoint(&observer)?;
-
-        let mut c = MembershipClient::connect(observer)
-            .map_err(|e| JoinError::Phase2(e.into()))
-            .await?;
+        let c = self.connect(&observer).map_err(JoinError::Phase2).await?;
 
-        (c.join(req).map_ok(|r| r.into_inner()))
-            .map_err(|e| JoinError::Phase2(e.into()))
+        (c.join(Request::new(req)).map_ok(|r| r.into_inner()))
+            .map_err(|e| match e.code() {
+                Code::PermissionDenied => JoinError::Rejected(e.message().to_owned()),
+                _ => JoinError::Phase2(e),
+            })
             .await
     }
+
+    /// Gracefully leave the active configuration by asking each of our observers to raise
+    /// down alerts for the local node. Once this has been called, the local node will not
+    /// attempt to rejoin if it is ejected.
+    ///
+    /// Resolves when a view-change that removes the local node is accepted, or after the
+    /// configured leave timeout if none is.
+    pub(crate) async fn leave(&self) {
+        if self.leaving.swap(true, SeqCst) {
+            return;
+        }
+
+        self.eject_local().await;
+    }
+
+    /// Ask each of our observers to raise down alerts for the local node, and wait until a
+    /// view-change that removes it is accepted, or the configured leave timeout elapses.
+    ///
+    /// Returns true if the local node was removed from the active configuration.
+    pub(super) async fn eject_local(&self) -> bool {
+        let mut cuts = self.subscribe();
+
+        let (req, observers) = {
+            let state = self.state.read().await;
+
+            let observers = match self.local_observers(&state) {
+                Some(observers) => observers,
+                None => return false,
+            };
+
+            let req = LeaveReq {
+                sender: self.local_node(),
+                conf_id: state.conf_id,
+            };
+
+            (req, observers)
+        };
+
+        info!("leaving: conf_id={}", req.conf_id);
+
+        let leave = |observer| self.request_leave(req.clone(), observer);
+        if let Err(e) = self.ask_observers(&observers, leave).await {
+            warn!("leave failed: {}", e);
+            return false;
+        }
+
+        let max_wait = self.cfg.timings.leave_timeout;
+        let ejected = async {
+            while let Ok(cut) = cuts.recv().await {
+                if cut.is_degraded() {
+                    break;
+                }
+            }
+        };
+
+        if timeout(max_wait, ejected).await.is_err() {
+            warn!("leave timed out: waited {:?}", max_wait);
+            return false;
+        }
+
+        true
+    }
+
+    /// Replace the local node's metadata with the result of applying `f` to it, and ask
+    /// each of our observers to propagate the change to the rest of the configuration.
+    ///
+    /// The new metadata is retained even if this fails, and will be used if the local node
+    /// rejoins.
+    pub(crate) async fn update_metadata<F>(&self, f: F) -> Grpc<()>
+    where F: FnOnce(&mut HashMap<String, Vec<u8>>) {
+        let (req, observers) = {
+            let mut state = self.state.write().await;
+            f(&mut state.meta);
+
+            let observers = (self.local_observers(&state))
+                .ok_or_else(|| Status::unavailable("not in configuration"))?;
+
+            let req = UpdateMetadataReq {
+                sender: self.local_node(),
+                conf_id: state.conf_id,
+                meta: state.meta.clone(),
+            };
+
+            (req, observers)
+        };
+
+        info!("updating metadata: conf_id={}", req.conf_id);
+
+        let update = |observer| self.request_update(req.clone(), observer);
+        self.ask_observers(&observers, update).await
+    }
+
+    /// Returns the local node's observers in the active configuration, or `None` if the
+    /// local node isn't a member of it.
+    fn local_observers(&self, state: &State) -> Option<Vec<Endpoint>> {
+        let local_node = self.local_node();
+        guard! { state.nodes.contains(&local_node) };
+
+        let mut observers: Vec<_> = state.nodes.predecessors(&local_node).cloned().collect();
+        observers.sort();
+        observers.dedup();
+        Some(observers)
+    }
+
+    /// Concurrently send a request to each of `observers`. Succeeds if any of them accepted
+    /// it, otherwise returns one of the errors encountered.
+    ///
+    /// Waits for every request to complete (or time out) rather than returning on the first
+    /// success, as the rest of the observers' alerts are needed to reach a view-change.
+    async fn ask_observers<'a, F, R>(&self, observers: &'a [Endpoint], ask: F) -> Grpc<()>
+    where
+        F: Fn(&'a Endpoint) -> R,
+        R: Future<Output = Grpc<()>>,
+    {
+        let results: Vec<_> = (observers.iter())
+            .map(|observer| timeout(self.cfg.fd_timeout, ask(observer)))
+            .collect::<FuturesUnordered<_>>()
+            .collect()
+            .await;
+
+        let mut e = Status::unavailable("no observers");
+        for r in results {
+            match r {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(err)) => e = err,
+                Err(err) => e = Status::deadline_exceeded(err.to_string()),
+            }
+        }
+
+        Err(e)
+    }
+
+    /// Ask `observer` to raise down alerts for the local node.
+    async fn request_leave(&self, req: LeaveReq, observer: &Endpoint) -> Grpc<()> {
+        let c = self.connect(observer).await?;
+        c.leave(Request::new(req)).await.map(|_| {})
+    }
+
+    /// Ask `peer` which configuration it's a member of.
+    async fn request_discover(&self, req: DiscoverReq, peer: &Endpoint) -> Grpc<DiscoverResp> {
+        let c = self.connect(peer).await?;
+        c.discover(Request::new(req)).await.map(|r| r.into_inner())
+    }
+
+    /// Ask `observer` to raise metadata update alerts for the local node.
+    async fn request_update(&self, req: UpdateMetadataReq, observer: &Endpoint) -> Grpc<()> {
+        let c = self.connect(observer).await?;
+        c.update_metadata(Request::new(req)).await.map(|_| {})
+    }
 }
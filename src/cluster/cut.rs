@@ -6,8 +6,9 @@
 // copied, modified, or distributed except according to those terms.
 //! Multi-node cuts and friends.
 use super::{proto, Metadata, State};
+use crate::rng::local_rng;
 use futures::stream::{unfold, Stream};
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::{
     collections::HashMap,
     convert::TryInto,
@@ -83,10 +84,13 @@ impl Subscription {
 pub struct MultiNodeCut {
     pub(crate) skipped: u64,
     pub(crate) local_addr: SocketAddr,
+    pub(crate) cluster_name: Arc<str>,
     pub(crate) conf_id: u64,
     pub(crate) degraded: bool,
+    pub(crate) merged: bool,
     pub(crate) members: Arc<[Member]>,
     pub(crate) joined: Arc<[Member]>,
+    pub(crate) updated: Arc<[Member]>,
     pub(crate) kicked: Arc<[Member]>,
 }
 
@@ -109,20 +113,26 @@ impl MultiNodeCut {
     /// Returns the number of cuts that were skipped between this and the last received
     /// cut.
     ///
-    /// If this isn't 0, [joined](MultiNodeCut::joined) and [kicked](MultiNodeCut::kicked)
-    /// most likely do not represent the complete set of membership changes since the last
-    /// cut.
+    /// If this isn't 0, [joined](MultiNodeCut::joined), [updated](MultiNodeCut::updated),
+    /// and [kicked](MultiNodeCut::kicked) most likely do not represent the complete set of
+    /// membership changes since the last cut.
     ///
     /// On the other hand, [members](MultiNodeCut::members) will always be complete.
     pub fn skipped(&self) -> u64 {
         self.skipped
     }
 
-    /// Returns the local node's listening address.
+    /// Returns the address the local node is reachable at, which is the address it was
+    /// served on unless [Mesh::advertise_addr][crate::Mesh::advertise_addr] was set.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
 
+    /// Returns the name of the cluster the configuration belongs to.
+    pub fn cluster_name(&self) -> &str {
+        &self.cluster_name
+    }
+
     /// Returns the accepted configuration id.
     pub fn conf_id(&self) -> u64 {
         self.conf_id
@@ -133,12 +143,20 @@ impl MultiNodeCut {
         self.degraded
     }
 
+    /// Returns true if the local node left a smaller configuration that had formed apart from
+    /// this one, and joined this one in order to merge them.
+    ///
+    /// See [Mesh::merge_partitions][crate::Mesh::merge_partitions].
+    pub fn is_merged(&self) -> bool {
+        self.merged
+    }
+
     /// Returns a random healthy member.
     ///
     /// # Panics
     /// Panics if the configuration is empty.
     pub(crate) fn random_member(&self) -> &Member {
-        &self.members[thread_rng().gen_range(0..self.members.len())]
+        &self.members[local_rng().gen_range(0..self.members.len())]
     }
 
     /// Returns all members in the configuration.
@@ -151,6 +169,11 @@ impl MultiNodeCut {
         &self.joined
     }
 
+    /// Returns any members that updated their metadata.
+    pub fn updated(&self) -> &Arc<[Member]> {
+        &self.updated
+    }
+
     /// Returns any members that were kicked.
     pub fn kicked(&self) -> &Arc<[Member]> {
         &self.kicked
@@ -5,103 +5,610 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 //! Distributed fault detection.
+//!
+//! Each member continuously probes the subjects it observes in the configuration. The outcome
+//! of every probe is fed into a [FaultDetector], which decides when a subject has become
+//! faulty and should be reported to the rest of the mesh.
 use super::{
     cut::{self, Subscription},
-    proto::{membership_client::MembershipClient, Ack, Edge, Endpoint},
+    proto::{Ack, Edge, Endpoint, ProbeViaReq},
     Cluster,
 };
+use crate::rng::local_rng;
 use futures::{
     future::{join, FutureExt},
     stream::{FuturesUnordered, StreamExt},
 };
-use std::{collections::HashMap, sync::Arc};
+use log::warn;
+use rand::seq::SliceRandom;
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     select,
-    time::{sleep, timeout},
+    time::{sleep, timeout, Instant},
 };
+use tonic::Request;
+
+/// Decides when a subject should be reported as faulty, given the outcomes of the probes
+/// sent to it.
+///
+/// A single detector is shared by every subject the local node observes, and is consulted
+/// after each round of probes. Once it reports a subject as faulty, the local node raises
+/// down alerts for that subject.
+pub trait FaultDetector: Send + Sync + 'static {
+    /// Record the outcome of a probe sent to `subject`. `rtt` is the round-trip time of the
+    /// probe if it succeeded, or `None` if it failed or timed out.
+    ///
+    /// If the subject only responded to another member that probed it on our behalf, `rtt`
+    /// is the round-trip time of asking that member to do so.
+    fn record(&mut self, subject: SocketAddr, rtt: Option<Duration>);
+
+    /// Returns whether `subject` should be reported as faulty.
+    fn is_faulty(&self, subject: SocketAddr) -> bool;
+
+    /// Returns the number of successive probes to `subject` that have failed since it last
+    /// responded. Subjects with any strikes are published as [Suspicion]s.
+    fn strikes(&self, subject: SocketAddr) -> usize;
+
+    /// Forget any state associated with `subject`. This is called after `subject` has been
+    /// reported as faulty, and when it is no longer observed by the local node.
+    fn forget(&mut self, subject: SocketAddr);
+}
+
+/// A subject that the local node currently suspects to be faulty, because its last probe
+/// failed.
+///
+/// Suspicions are advisory, and local to the member that holds them. They're updated after
+/// every round of probes, well before a faulty subject is removed from the configuration by
+/// a view-change (which may never happen, if its other observers can still reach it).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Suspicion {
+    addr: SocketAddr,
+    strikes: usize,
+    last_rtt: Option<Duration>,
+}
+
+impl Suspicion {
+    /// Returns the address of the suspected subject.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the number of successive probes to the subject that have failed, as counted by
+    /// the local node's [FaultDetector].
+    pub fn strikes(&self) -> usize {
+        self.strikes
+    }
+
+    /// Returns the round-trip time of the last direct probe to the subject that succeeded, if
+    /// any have.
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+}
+
+/// The outcome of probing a subject.
+#[derive(Copy, Clone, Debug)]
+enum Probe {
+    /// The subject responded to a direct probe, with some round-trip time.
+    Direct(Duration),
+    /// The subject responded to another member that probed it on our behalf. Holds the
+    /// round-trip time of asking that member to do so.
+    Indirect(Duration),
+    /// The subject didn't respond.
+    Failed,
+}
+
+impl Probe {
+    /// Returns the round-trip time of the probe, if it succeeded.
+    fn rtt(self) -> Option<Duration> {
+        match self {
+            Probe::Direct(rtt) | Probe::Indirect(rtt) => Some(rtt),
+            Probe::Failed => None,
+        }
+    }
+}
+
+/// A fault detector that reports subjects after a fixed number of successive probe failures.
+///
+/// This is the default detector, and is configured by [Mesh::fault_strikes][strikes].
+///
+/// [strikes]: crate::Mesh::fault_strikes
+#[derive(Clone, Debug)]
+pub struct StrikeDetector {
+    strikes: usize,
+    faults: HashMap<SocketAddr, usize>,
+}
+
+impl StrikeDetector {
+    /// Create a detector that reports subjects after `strikes` successive probe failures.
+    ///
+    /// # Panics
+    /// Panics if `strikes == 0`.
+    pub fn new(strikes: usize) -> Self {
+        assert!(strikes != 0);
+
+        Self {
+            strikes,
+            faults: HashMap::new(),
+        }
+    }
+}
+
+impl FaultDetector for StrikeDetector {
+    fn record(&mut self, subject: SocketAddr, rtt: Option<Duration>) {
+        let faults = self.faults.entry(subject).or_default();
+
+        match rtt {
+            Some(_) => *faults = 0,
+            None => *faults += 1,
+        }
+    }
+
+    fn is_faulty(&self, subject: SocketAddr) -> bool {
+        self.strikes(subject) >= self.strikes
+    }
+
+    fn strikes(&self, subject: SocketAddr) -> usize {
+        self.faults.get(&subject).copied().unwrap_or_default()
+    }
+
+    fn forget(&mut self, subject: SocketAddr) {
+        self.faults.remove(&subject);
+    }
+}
+
+/// A fault detector based on [the φ accrual failure detector][phi].
+///
+/// Rather than counting failures, this tracks the intervals between successful probes of
+/// each subject, and computes how suspicious the time since the last success is given that
+/// history. This adapts to the latency characteristics of each link, and so copes with both
+/// local and cross-region subjects within the same mesh.
+///
+/// [phi]: https://doi.org/10.1109/RELDIS.2004.1353004
+#[derive(Clone, Debug)]
+pub struct PhiAccrualDetector {
+    threshold: f64,
+    window: usize,
+    min_std_dev: Duration,
+    acceptable_pause: Duration,
+    first_interval: Duration,
+    history: HashMap<SocketAddr, History>,
+}
+
+#[derive(Clone, Debug)]
+struct History {
+    last: Instant,
+    intervals: VecDeque<f64>,
+    failures: usize,
+}
+
+impl Default for PhiAccrualDetector {
+    fn default() -> Self {
+        Self::new(8.0)
+    }
+}
+
+impl PhiAccrualDetector {
+    /// Create a detector that reports subjects once their φ exceeds `threshold`.
+    ///
+    /// A threshold of 8 (the default) is a reasonable starting point. Lower thresholds will
+    /// detect faults sooner, at the cost of more false positives.
+    ///
+    /// # Panics
+    /// Panics if `threshold` isn't positive.
+    pub fn new(threshold: f64) -> Self {
+        assert!(threshold > 0.0);
+
+        Self {
+            threshold,
+            window: 100,
+            min_std_dev: Duration::from_millis(100),
+            acceptable_pause: Duration::from_secs(0),
+            first_interval: Duration::from_secs(2),
+            history: HashMap::new(),
+        }
+    }
+
+    /// Set the number of probe intervals to consider for each subject.
+    ///
+    /// Defaults to 100.
+    ///
+    /// # Panics
+    /// Panics if `window == 0`.
+    pub fn window(mut self, window: usize) -> Self {
+        assert!(window != 0);
+        self.window = window;
+        self
+    }
+
+    /// Set the minimum standard deviation of probe intervals. This prevents overly eager
+    /// detection when intervals are very regular.
+    ///
+    /// Defaults to 100 milliseconds.
+    pub fn min_std_dev(mut self, min: Duration) -> Self {
+        self.min_std_dev = min;
+        self
+    }
+
+    /// Set an additional amount of time that is tolerated between successful probes before
+    /// a subject becomes suspicious.
+    ///
+    /// Defaults to 0.
+    pub fn acceptable_pause(mut self, pause: Duration) -> Self {
+        self.acceptable_pause = pause;
+        self
+    }
+
+    /// Set the expected interval between probes of a subject, which is used until enough
+    /// history has been gathered. This should be close to the configured
+    /// [fault_timeout][timeout].
+    ///
+    /// Defaults to 2 seconds.
+    ///
+    /// [timeout]: crate::Mesh::fault_timeout
+    pub fn first_interval(mut self, interval: Duration) -> Self {
+        self.first_interval = interval;
+        self
+    }
+
+    fn record_at(&mut self, subject: SocketAddr, rtt: Option<Duration>, now: Instant) {
+        let first = self.first_interval.as_secs_f64() * 1000.0;
+        let window = self.window;
+
+        let h = match self.history.entry(subject) {
+            Entry::Occupied(o) => o.into_mut(),
+
+            // seed the history with a guess, as if the subject had just responded.
+            Entry::Vacant(v) => {
+                let mut intervals = VecDeque::with_capacity(window);
+                intervals.push_back(first - first / 4.0);
+                intervals.push_back(first + first / 4.0);
+                v.insert(History {
+                    last: now,
+                    intervals,
+                    failures: rtt.is_none() as usize,
+                });
+                return;
+            }
+        };
+
+        if rtt.is_none() {
+            h.failures += 1;
+            return;
+        }
+
+        let interval = now.saturating_duration_since(h.last).as_secs_f64() * 1000.0;
+        if h.intervals.len() >= window {
+            h.intervals.pop_front();
+        }
+        h.intervals.push_back(interval);
+        h.last = now;
+        h.failures = 0;
+    }
+
+    fn phi_at(&self, subject: SocketAddr, now: Instant) -> f64 {
+        let h = match self.history.get(&subject) {
+            Some(h) => h,
+            None => return 0.0,
+        };
+
+        let n = h.intervals.len() as f64;
+        let mean = h.intervals.iter().sum::<f64>() / n;
+        let var = h.intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / n;
+        let std_dev = var.sqrt().max(self.min_std_dev.as_secs_f64() * 1000.0);
+
+        let elapsed = now.saturating_duration_since(h.last);
+        let t = elapsed.saturating_sub(self.acceptable_pause).as_secs_f64() * 1000.0;
+
+        // a logistic approximation of the normal cdf, as used by akka and cassandra.
+        let y = (t - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+
+        if t > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+impl FaultDetector for PhiAccrualDetector {
+    fn record(&mut self, subject: SocketAddr, rtt: Option<Duration>) {
+        self.record_at(subject, rtt, Instant::now());
+    }
+
+    fn is_faulty(&self, subject: SocketAddr) -> bool {
+        self.phi_at(subject, Instant::now()) >= self.threshold
+    }
+
+    fn strikes(&self, subject: SocketAddr) -> usize {
+        self.history.get(&subject).map_or(0, |h| h.failures)
+    }
+
+    fn forget(&mut self, subject: SocketAddr) {
+        self.history.remove(&subject);
+    }
+}
 
 impl Cluster {
     /// Run the fault detector until the cluster is brought down.
     pub(crate) async fn detect_faults(self: Arc<Self>, mut cuts: Subscription) -> cut::Result {
+        let mut observed = HashSet::new();
+        let mut rtts = HashMap::new();
+
         loop {
             select! {
-                _ = self.spin_fd_probes() => {}
+                _ = self.spin_fd_probes(&mut observed, &mut rtts) => {}
                 cut = cuts.recv() => { cut?; }
             }
         }
     }
 
     /// Initialize a fault detection round and continuously probe all observed subjects. Edge
-    /// failures are reported to the rest of the cluster if the configured number of successive
-    /// faults is encountered.
+    /// failures are reported to the rest of the cluster once the fault detector considers a
+    /// subject to be faulty.
+    ///
+    /// `observed` holds the subjects from the previous round, which will be forgotten by the
+    /// fault detector if they aren't observed in this one. `rtts` holds the round-trip time
+    /// of the last successful probe to each of them, which is published with [Suspicion]s.
     ///
     /// Resolves (and should be restarted) when the next view-change proposal is accepted.
-    async fn spin_fd_probes(self: &Arc<Self>) {
-        let (conf_id, mut subjects) = async {
-            #[derive(Default)]
-            struct Subject {
-                rings: Vec<u64>,
-                faults: usize,
-            }
-
-            let mut subjects: HashMap<_, Subject> = HashMap::with_capacity(self.cfg.k);
+    async fn spin_fd_probes(
+        self: &Arc<Self>,
+        observed: &mut HashSet<SocketAddr>,
+        rtts: &mut HashMap<SocketAddr, Option<Duration>>,
+    ) {
+        let (conf_id, members, subjects) = async {
+            let mut subjects: BTreeMap<_, Vec<u64>> = BTreeMap::new();
             let state = self.state.read().await;
 
             // we might have been assigned the same subject on multiple rings, so we dedupe
             // by subject and track which rings we're authoritative over.
-            for (ring, subject) in (state.nodes.successors(&self.local_node()))
-                .cloned()
-                .enumerate()
-            {
-                subjects.entry(subject).or_default().rings.push(ring as u64);
+            for (ring, subject) in state.nodes.successor_edges(&self.local_node()) {
+                subjects.entry(subject.clone()).or_default().push(ring as u64);
             }
 
-            (state.conf_id, subjects)
+            let members: Vec<_> = (state.nodes.iter())
+                .filter(|e| **e != self.local_node())
+                .cloned()
+                .collect();
+
+            (state.conf_id, members, subjects)
         }
         .await;
 
+        // subjects are probed in order, so that indirect probes draw from the rng in the same
+        // order every time.
+        let subjects: BTreeMap<_, _> = (subjects.into_iter())
+            .map(|(e, rings)| {
+                let addr = SocketAddr::try_from(&e).expect("all stored endpoints are valid");
+                (e, (addr, rings))
+            })
+            .collect();
+
+        {
+            let current: HashSet<_> = subjects.values().map(|(addr, _)| *addr).collect();
+            let mut fd = self.fd.lock().unwrap();
+
+            for addr in observed.difference(&current) {
+                fd.forget(*addr);
+                self.cfg.metrics.forget_subject(*addr);
+                rtts.remove(addr);
+            }
+
+            *observed = current;
+            self.publish_suspicions(&**fd, rtts);
+        }
+
         loop {
             // start sending off probes, each of which times out after fd_timeout.
-            let probes = (subjects.iter_mut())
-                .map(|(e, s)| self.probe(e, &mut s.faults))
+            let probes = (subjects.iter())
+                .map(|(e, (addr, _))| {
+                    let probe = self.probe(e, &members);
+                    probe.map(move |(probe, seen)| (e.clone(), *addr, probe, seen))
+                })
                 .collect::<FuturesUnordered<_>>()
-                .for_each(|_| async {});
+                .collect::<Vec<_>>();
 
             // wait for all probes to finish, and for fd_timeout to elapse. this caps the
             // rate at which subjects are probed to k per fd_timeout.
-            let mut state = join(sleep(self.cfg.fd_timeout), probes)
-                .then(|_| self.state.write())
-                .await;
+            let (_, outcomes) = join(sleep(self.cfg.fd_timeout), probes).await;
+
+            {
+                let mut fd = self.fd.lock().unwrap();
+                for (_, addr, probe, _) in outcomes.iter() {
+                    fd.record(*addr, probe.rtt());
+
+                    // indirect probes take a longer path, so they're recorded separately.
+                    let last_rtt = rtts.entry(*addr).or_default();
+                    match *probe {
+                        Probe::Direct(rtt) => {
+                            self.cfg.metrics.record_probe(*addr, Some(rtt));
+                            *last_rtt = Some(rtt);
+                        }
+                        Probe::Indirect(rtt) => self.cfg.metrics.record_indirect_probe(rtt),
+                        Probe::Failed => self.cfg.metrics.record_probe(*addr, None),
+                    }
+                }
+
+                self.publish_suspicions(&**fd, rtts);
+            }
+
+            let mut state = self.state.write().await;
 
             // if there's been a view-change, subjects may have become invalidated.
             if state.conf_id != conf_id {
                 break;
             }
 
-            // subjects are marked as faulted if they failed fd_strikes successive probes.
-            let faulted = (subjects.iter_mut())
-                .filter(|(_, s)| s.faults >= self.cfg.fd_strikes)
-                .flat_map(|(e, s)| {
-                    s.faults = 0;
-                    s.rings.iter().map(move |ring| Edge::down(e.clone(), *ring))
-                });
+            // a subject in a configuration we've never seen most likely accepted a
+            // view-change that we missed the broadcast of.
+            let ahead = (outcomes.iter())
+                .find(|(.., seen)| matches!(seen, Some(id) if !state.has_seen_config(*id)))
+                .map(|(e, ..)| e.clone());
+
+            if let Some(peer) = ahead {
+                drop(state);
+                if let Err(e) = self.catch_up(&peer, conf_id).await {
+                    warn!("catch up failed: {}", e);
+                }
+                continue;
+            }
+
+            // subjects are marked as faulted once the fault detector gives up on them.
+            let mut faulted = Vec::new();
+            {
+                let mut fd = self.fd.lock().unwrap();
+                for (e, (addr, rings)) in subjects.iter() {
+                    if fd.is_faulty(*addr) {
+                        fd.forget(*addr);
+                        faulted.extend(rings.iter().map(|ring| Edge::down(e.clone(), *ring)));
+                    }
+                }
+            }
 
             self.enqueue_edges(&mut *state, faulted);
         }
     }
 
-    /// Probe a subject, modifying the successive `faults` counter appropriately upon success
-    /// or failure.
-    async fn probe(&self, subject: &Endpoint, faults: &mut usize) {
+    /// Publish the subjects in `rtts` that `fd` has any strikes against to any watchers of the
+    /// local node's suspicions, if they've changed.
+    fn publish_suspicions(
+        &self,
+        fd: &dyn FaultDetector,
+        rtts: &HashMap<SocketAddr, Option<Duration>>,
+    ) {
+        let mut suspicions: Vec<_> = (rtts.iter())
+            .map(|(addr, last_rtt)| Suspicion {
+                addr: *addr,
+                strikes: fd.strikes(*addr),
+                last_rtt: *last_rtt,
+            })
+            .filter(|s| s.strikes > 0)
+            .collect();
+
+        suspicions.sort_by_key(|s| s.addr);
+
+        if self.suspicions_rx.borrow()[..] != suspicions[..] {
+            let _ = self.suspicions.send(suspicions.into());
+        }
+    }
+
+    /// Probe a subject, returning the outcome as well as the configuration it's a member of
+    /// if it responded to a direct probe.
+    ///
+    /// If the subject doesn't respond to a direct probe within fd_timeout, up to
+    /// `indirect_probes` randomly chosen `members` are asked to probe it on our behalf. The
+    /// probe only fails if none of them get a response either.
+    async fn probe(
+        &self,
+        subject: &Endpoint,
+        members: &[Endpoint],
+    ) -> (Probe, Option<u64>) {
+        if let Some((rtt, conf_id)) = self.probe_direct(subject).await {
+            return (Probe::Direct(rtt), Some(conf_id));
+        }
+
+        let via = (members.iter())
+            .filter(|m| *m != subject)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut probes = (via.choose_multiple(&mut local_rng(), self.cfg.indirect_probes))
+            .map(|m| self.probe_indirect(m, subject))
+            .collect::<FuturesUnordered<_>>();
+
+        while let Some(rtt) = probes.next().await {
+            if let Some(rtt) = rtt {
+                return (Probe::Indirect(rtt), None);
+            }
+        }
+
+        (Probe::Failed, None)
+    }
+
+    /// Probe a subject directly, returning the round-trip time and the configuration it's a
+    /// member of if it responded within fd_timeout.
+    pub(crate) async fn probe_direct(&self, subject: &Endpoint) -> Option<(Duration, u64)> {
+        let start = Instant::now();
+
         let send_probe = timeout(self.cfg.fd_timeout, async {
-            let e = self.resolve_endpoint(subject).ok()?;
-            let mut c = MembershipClient::connect(e).await.ok()?;
-            c.probe(Ack {}).await.ok()
+            let c = self.connect(subject).await.ok()?;
+            c.probe(Request::new(Ack {})).await.ok()
         });
 
-        match send_probe.await.ok().flatten() {
-            Some(_) => *faults = 0,
-            None => *faults += 1,
+        let resp = send_probe.await.ok().flatten()?.into_inner();
+        Some((start.elapsed(), resp.conf_id))
+    }
+
+    /// Ask `via` to probe a subject on our behalf, returning the round-trip time of asking
+    /// `via` if the subject responded.
+    async fn probe_indirect(&self, via: &Endpoint, subject: &Endpoint) -> Option<Duration> {
+        let start = Instant::now();
+
+        // via will itself wait up to fd_timeout for the subject to respond.
+        let send_probe = timeout(self.cfg.fd_timeout * 2, async {
+            let c = self.connect(via).await.ok()?;
+            let subject = subject.clone();
+            c.probe_via(Request::new(ProbeViaReq { subject })).await.ok()
+        });
+
+        send_probe.await.ok().flatten()?;
+        Some(start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        ([127, 0, 0, 1], 10000).into()
+    }
+
+    #[test]
+    fn strikes_are_successive() {
+        let mut fd = StrikeDetector::new(3);
+
+        fd.record(addr(), None);
+        fd.record(addr(), None);
+        fd.record(addr(), Some(Duration::from_millis(1)));
+        fd.record(addr(), None);
+        fd.record(addr(), None);
+        assert!(!fd.is_faulty(addr()));
+
+        assert_eq!(fd.strikes(addr()), 2);
+
+        fd.record(addr(), None);
+        assert!(fd.is_faulty(addr()));
+
+        fd.forget(addr());
+        assert!(!fd.is_faulty(addr()));
+        assert_eq!(fd.strikes(addr()), 0);
+    }
+
+    #[test]
+    fn phi_accrues_without_responses() {
+        let mut fd = PhiAccrualDetector::default();
+        let start = Instant::now();
+        let interval = Duration::from_secs(1);
+
+        for i in 0..20 {
+            fd.record_at(addr(), Some(Duration::from_millis(1)), start + interval * i);
         }
+
+        let last = start + interval * 19;
+        assert!(fd.phi_at(addr(), last + interval) < 1.0);
+        assert!(fd.phi_at(addr(), last + interval * 3) > fd.threshold);
+
+        fd.record_at(addr(), None, last + interval);
+        fd.record_at(addr(), None, last + interval * 2);
+        assert_eq!(fd.strikes(addr()), 2);
     }
 }
@@ -0,0 +1,151 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Detection and merging of configurations that formed apart from each other, such as after
+//! a long partition or a bad bootstrap.
+use super::{
+    cut::{self, MultiNodeCut, Subscription},
+    proto::Endpoint,
+    Cluster,
+};
+use futures::future::pending;
+use log::{info, warn};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    convert::TryFrom,
+    net::SocketAddr,
+    sync::{atomic::Ordering::SeqCst, Arc},
+};
+use tokio::{
+    select,
+    time::{interval, timeout},
+};
+
+impl Cluster {
+    /// Periodically ask the configured merge peers, seeds, and bootstrap peers, as well as any
+    /// nodes previously seen in a configuration, which configuration they're a member of. If
+    /// any of them is a member of a larger configuration than ours, the member of ours with the
+    /// lowest address leaves it and joins theirs.
+    ///
+    /// The rest of our members do the same on later ticks, each once it's the member with the
+    /// lowest address left, so the smaller configuration is merged into the larger one a member
+    /// at a time.
+    pub(crate) async fn merge_partitions(self: Arc<Self>, mut cuts: Subscription) -> cut::Result {
+        let period = match self.cfg.merge_interval {
+            Some(period) => period,
+            None => return pending().await,
+        };
+
+        let mut seen: HashSet<Endpoint> = (self.cfg.merge_peers.iter())
+            .chain(&self.cfg.seeds)
+            .chain(&self.cfg.bootstrap_peers)
+            .cloned()
+            .collect();
+
+        let mut ticks = interval(period);
+        let mut last_cut = None;
+
+        loop {
+            select! {
+                cut = cuts.recv() => {
+                    let cut = cut?;
+                    seen.extend(cut.members().iter().map(Endpoint::from));
+                    last_cut = Some(cut);
+                }
+
+                _ = ticks.tick() => {
+                    if let Some(cut) = last_cut.as_ref() {
+                        self.merge_into_larger(cut, &seen).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Join the largest configuration that any of `seen` (other than members of `cut`) is a
+    /// member of, if it's larger than `cut`.
+    ///
+    /// Configurations of the same size are ordered by id, so that exactly one of them is
+    /// merged into the other. Only the member of `cut` with the lowest address merges, after
+    /// leaving `cut`; the rest follow on later ticks, once each is the lowest remaining.
+    async fn merge_into_larger(&self, cut: &MultiNodeCut, seen: &HashSet<Endpoint>) {
+        if cut.is_degraded() || self.leaving.load(SeqCst) {
+            return;
+        }
+
+        // members are ordered by address, so only one of them merges at a time.
+        if cut.members()[0].addr() != self.addr {
+            return;
+        }
+
+        let members: HashSet<Endpoint> = cut.members().iter().map(Endpoint::from).collect();
+        let peers: Vec<_> = seen.difference(&members).cloned().collect();
+
+        let larger = (self.discover(&peers).await.into_iter())
+            .filter(|resp| resp.members > 0 && resp.conf_id != cut.conf_id())
+            .max_by_key(|resp| (resp.members, resp.conf_id))
+            .filter(|resp| (resp.members, resp.conf_id) > (members.len() as u64, cut.conf_id()));
+
+        let resp = match larger {
+            Some(resp) => resp,
+            None => return,
+        };
+
+        info!(
+            "merging: conf_id={} into conf_id={} via {}",
+            cut.conf_id(),
+            resp.conf_id,
+            resp.sender
+        );
+
+        self.merging.store(true, SeqCst);
+        self.merge_via(cut, &members, &resp.sender).await;
+        self.merging.store(false, SeqCst);
+    }
+
+    /// Leave `cut` and join the configuration `sender` is a member of.
+    ///
+    /// If that fails, the local node rejoins the rest of `members` for at most the configured
+    /// leave timeout, and bootstraps a new configuration if they can't be reached either. A
+    /// later tick can merge that configuration like any other.
+    async fn merge_via(&self, cut: &MultiNodeCut, members: &HashSet<Endpoint>, sender: &Endpoint) {
+        let ejected = members.len() > 1;
+        if ejected && !self.eject_local().await {
+            warn!("merge failed: couldn't leave conf_id={}", cut.conf_id());
+            return;
+        }
+
+        let max_wait = self.cfg.timings.join_timeout_max;
+        let e = match self.join_via(sender, max_wait, true).await {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        self.cfg.metrics.record_join_failure(&e);
+        warn!("merge failed: {}", e);
+
+        if !ejected {
+            return;
+        }
+
+        let mut seeds = (members.iter())
+            .filter(|e| SocketAddr::try_from(*e).ok() != Some(self.addr))
+            .cycle();
+        let rejoin = self.join_via_backoff(|| Cow::Borrowed(seeds.next().unwrap()));
+
+        let max_wait = self.cfg.timings.leave_timeout;
+        match timeout(max_wait, rejoin).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => warn!("rejoin failed: {}", e),
+            Err(_) => warn!("rejoin timed out: waited {:?}", max_wait),
+        }
+
+        let mut state = self.state.write().await;
+        state.clear_consensus();
+        state.clear_membership();
+        self.bootstrap(&mut state);
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Metrics describing the health of the membership protocol.
+//!
+//! Each member records into a [Metrics] registry, which can be obtained from
+//! [Mesh::metrics][metrics] before the mesh is served. The registry can be read directly, or
+//! encoded in the prometheus text exposition format with [Metrics::encode_prometheus].
+//!
+//! [metrics]: crate::Mesh::metrics
+use super::JoinError;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// The upper bounds (in seconds) of the buckets of every [Histogram].
+const BUCKETS: [f64; 14] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// The label values used for each [JoinError] variant.
+const JOIN_ERRORS: [&str; 5] = ["timed_out", "phase1", "phase2", "no_observers", "rejected"];
+
+/// A monotonically increasing count.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Returns the current count.
+    pub fn get(&self) -> u64 {
+        self.0.load(Relaxed)
+    }
+
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Relaxed);
+    }
+}
+
+/// A distribution of durations, counted in fixed buckets that range from 1ms to 30s.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    /// Returns the number of observed durations.
+    pub fn count(&self) -> u64 {
+        self.count.load(Relaxed)
+    }
+
+    /// Returns the sum of all observed durations.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.load(Relaxed))
+    }
+
+    /// Returns the upper bound of each bucket, along with the number of observed durations
+    /// that were less than or equal to it.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        (BUCKETS.iter().zip(self.buckets.iter()))
+            .map(|(le, n)| (Duration::from_secs_f64(*le), n.load(Relaxed)))
+    }
+
+    pub(crate) fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+
+        // buckets are cumulative, so every bucket at or above secs is incremented.
+        (BUCKETS.iter().zip(self.buckets.iter()))
+            .filter(|(le, _)| secs <= **le)
+            .for_each(|(_, n)| {
+                n.fetch_add(1, Relaxed);
+            });
+
+        self.count.fetch_add(1, Relaxed);
+        self.sum_us.fetch_add(d.as_micros() as u64, Relaxed);
+    }
+}
+
+/// A registry of metrics recorded by a member of the mesh.
+///
+/// Cloning this is cheap, and all clones refer to the same registry.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    inner: Arc<Registry>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    probe_rtt: Mutex<BTreeMap<SocketAddr, Arc<Histogram>>>,
+    indirect_probe_rtt: Histogram,
+    probe_failures: Counter,
+    alerts_sent: Counter,
+    alerts_received: Counter,
+    fast_rounds: Counter,
+    slow_rounds: Counter,
+    view_change_latency: Histogram,
+    broadcast_fanout: Counter,
+    broadcast_duplicates: Counter,
+    join_latency: Histogram,
+    join_failures: [Counter; JOIN_ERRORS.len()],
+}
+
+impl Metrics {
+    /// Create a new, empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the round-trip times of successful direct probes sent to `subject`, if it's
+    /// currently observed by the local node.
+    pub fn probe_rtt(&self, subject: SocketAddr) -> Option<Arc<Histogram>> {
+        self.inner.probe_rtt.lock().unwrap().get(&subject).cloned()
+    }
+
+    /// Returns the round-trip times of asking other members to probe subjects that didn't
+    /// respond directly, for those probes that succeeded.
+    pub fn indirect_probe_rtt(&self) -> &Histogram {
+        &self.inner.indirect_probe_rtt
+    }
+
+    /// Returns the number of probes that went unanswered, both directly and indirectly.
+    pub fn probe_failures(&self) -> &Counter {
+        &self.inner.probe_failures
+    }
+
+    /// Returns the number of cut detection alerts broadcast by the local node.
+    pub fn alerts_sent(&self) -> &Counter {
+        &self.inner.alerts_sent
+    }
+
+    /// Returns the number of cut detection alerts received by the local node.
+    pub fn alerts_received(&self) -> &Counter {
+        &self.inner.alerts_received
+    }
+
+    /// Returns the number of view-changes that were decided by a fast paxos round.
+    pub fn fast_rounds(&self) -> &Counter {
+        &self.inner.fast_rounds
+    }
+
+    /// Returns the number of view-changes that were decided by a classical paxos round.
+    pub fn slow_rounds(&self) -> &Counter {
+        &self.inner.slow_rounds
+    }
+
+    /// Returns the time from the first alert of each view-change being received to it being
+    /// applied.
+    pub fn view_change_latency(&self) -> &Histogram {
+        &self.inner.view_change_latency
+    }
+
+    /// Returns the number of broadcast messages forwarded to other members.
+    pub fn broadcast_fanout(&self) -> &Counter {
+        &self.inner.broadcast_fanout
+    }
+
+    /// Returns the number of broadcast messages that were dropped because they had already
+    /// been delivered.
+    pub fn broadcast_duplicates(&self) -> &Counter {
+        &self.inner.broadcast_duplicates
+    }
+
+    /// Returns the time taken to join the mesh, including any failed attempts.
+    pub fn join_latency(&self) -> &Histogram {
+        &self.inner.join_latency
+    }
+
+    /// Returns the number of failed join attempts, labeled by the kind of [JoinError] that
+    /// was encountered.
+    pub fn join_failures(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        (JOIN_ERRORS.iter().zip(self.inner.join_failures.iter())).map(|(l, n)| (*l, n.get()))
+    }
+
+    /// Encode all metrics in the prometheus text exposition format.
+    pub fn encode_prometheus(&self) -> String {
+        let mut buf = String::new();
+        self.encode(&mut buf).expect("writing to a string can't fail");
+        buf
+    }
+
+    pub(crate) fn record_probe(&self, subject: SocketAddr, rtt: Option<Duration>) {
+        match rtt {
+            Some(rtt) => (self.inner.probe_rtt.lock().unwrap())
+                .entry(subject)
+                .or_default()
+                .observe(rtt),
+            None => self.inner.probe_failures.inc(),
+        }
+    }
+
+    pub(crate) fn record_indirect_probe(&self, rtt: Duration) {
+        self.inner.indirect_probe_rtt.observe(rtt);
+    }
+
+    pub(crate) fn forget_subject(&self, subject: SocketAddr) {
+        self.inner.probe_rtt.lock().unwrap().remove(&subject);
+    }
+
+    pub(crate) fn record_join_failure(&self, e: &JoinError) {
+        let i = match e {
+            JoinError::TimedOut(_) => 0,
+            JoinError::Phase1(_) => 1,
+            JoinError::Phase2(_) => 2,
+            JoinError::NoObservers => 3,
+            JoinError::Rejected(_) => 4,
+        };
+        self.inner.join_failures[i].inc();
+    }
+
+    fn encode(&self, w: &mut String) -> fmt::Result {
+        let r = &*self.inner;
+
+        header(w, "blip_probe_rtt_seconds", "histogram", "Round-trip time of direct probes.")?;
+        for (subject, h) in r.probe_rtt.lock().unwrap().iter() {
+            histogram(w, "blip_probe_rtt_seconds", &format!("subject=\"{}\"", subject), h)?;
+        }
+
+        let name = "blip_indirect_probe_rtt_seconds";
+        header(w, name, "histogram", "Round-trip time of successful indirect probes.")?;
+        histogram(w, name, "", &r.indirect_probe_rtt)?;
+
+        #[rustfmt::skip]
+        let counters = [
+            ("blip_probe_failures_total", "Probes that went unanswered.", &r.probe_failures),
+            ("blip_alerts_sent_total", "Cut detection alerts sent.", &r.alerts_sent),
+            ("blip_alerts_received_total", "Cut detection alerts received.", &r.alerts_received),
+            ("blip_broadcast_fanout_total", "Broadcasts forwarded to members.", &r.broadcast_fanout),
+            ("blip_broadcast_duplicates_total", "Redundant broadcast deliveries.", &r.broadcast_duplicates),
+        ];
+
+        for (name, help, c) in counters.iter() {
+            header(w, name, "counter", help)?;
+            writeln!(w, "{} {}", name, c.get())?;
+        }
+
+        let name = "blip_view_changes_total";
+        header(w, name, "counter", "View-changes applied, by kind of paxos round.")?;
+        writeln!(w, "{}{{round=\"fast\"}} {}", name, r.fast_rounds.get())?;
+        writeln!(w, "{}{{round=\"slow\"}} {}", name, r.slow_rounds.get())?;
+
+        let name = "blip_view_change_latency_seconds";
+        header(w, name, "histogram", "Time from first alert to applied view-change.")?;
+        histogram(w, name, "", &r.view_change_latency)?;
+
+        let name = "blip_join_latency_seconds";
+        header(w, name, "histogram", "Time taken to join the mesh.")?;
+        histogram(w, name, "", &r.join_latency)?;
+
+        let name = "blip_join_failures_total";
+        header(w, name, "counter", "Failed join attempts, by error.")?;
+        for (reason, n) in self.join_failures() {
+            writeln!(w, "{}{{error=\"{}\"}} {}", name, reason, n)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn header(w: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(w, "# HELP {} {}", name, help)?;
+    writeln!(w, "# TYPE {} {}", name, kind)
+}
+
+fn histogram(w: &mut String, name: &str, labels: &str, h: &Histogram) -> fmt::Result {
+    let sep = if labels.is_empty() { "" } else { "," };
+
+    for (le, n) in BUCKETS.iter().zip(h.buckets.iter()) {
+        let n = n.load(Relaxed);
+        writeln!(w, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, le, n)?;
+    }
+    writeln!(w, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, h.count())?;
+
+    let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+    writeln!(w, "{}_sum{} {}", name, labels, h.sum().as_secs_f64())?;
+    writeln!(w, "{}_count{} {}", name, labels, h.count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let h = Histogram::default();
+        h.observe(Duration::from_millis(20));
+        h.observe(Duration::from_secs(60));
+
+        let buckets: Vec<_> = h.buckets().map(|(_, n)| n).collect();
+        assert_eq!(&[0, 0, 0, 0, 1, 1], &buckets[..6]);
+        assert_eq!(1, buckets[BUCKETS.len() - 1]);
+        assert_eq!(2, h.count());
+        assert_eq!(Duration::from_millis(60020), h.sum());
+    }
+
+    #[test]
+    fn prometheus_encoding() {
+        let m = Metrics::new();
+        let subject: SocketAddr = ([127, 0, 0, 1], 10000).into();
+        m.record_probe(subject, Some(Duration::from_millis(3)));
+        m.record_probe(subject, None);
+        m.record_indirect_probe(Duration::from_millis(40));
+        m.record_join_failure(&JoinError::NoObservers);
+        m.fast_rounds().inc();
+
+        let text = m.encode_prometheus();
+        for line in &[
+            "# TYPE blip_probe_rtt_seconds histogram",
+            "blip_probe_rtt_seconds_bucket{subject=\"127.0.0.1:10000\",le=\"0.0025\"} 0",
+            "blip_probe_rtt_seconds_bucket{subject=\"127.0.0.1:10000\",le=\"0.005\"} 1",
+            "blip_probe_rtt_seconds_count{subject=\"127.0.0.1:10000\"} 1",
+            "blip_indirect_probe_rtt_seconds_bucket{le=\"0.025\"} 0",
+            "blip_indirect_probe_rtt_seconds_count 1",
+            "blip_probe_failures_total 1",
+            "blip_view_changes_total{round=\"fast\"} 1",
+            "blip_join_latency_seconds_count 0",
+            "blip_join_failures_total{error=\"no_observers\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {:?} in:\n{}", line, text);
+        }
+    }
+}
@@ -12,66 +12,114 @@
 //!
 //! [rapid]: https://arxiv.org/abs/1803.03620
 //! [fpx]: https://www.microsoft.com/en-us/research/wp-content/uploads/2016/02/tr-2005-112.pdf
+pub mod admin;
+pub mod admission;
+mod auth;
 mod bootstrap;
 pub mod cut;
-mod faultdetect;
+pub mod faultdetect;
+mod merge;
+pub mod metrics;
+mod persist;
 mod proto;
+pub mod transport;
 
-use super::collections::{EventFilter, EventId, FreqSet, Tumbler};
+use super::{
+    collections::{EventFilter, EventId, FreqSet, Tumbler},
+    overlay::ProtocolTimings,
+    rng::local_rng,
+};
+use admission::{Admission, Joiner};
 use cut::{Member, MultiNodeCut, Subscription};
+use faultdetect::{FaultDetector, StrikeDetector, Suspicion};
+use metrics::Metrics;
 use proto::{
     broadcast_req::{Broadcasted::*, *},
-    membership_client::*,
     membership_server::*,
     *,
 };
+use transport::{Admit, Authenticated, Client, Transport, Verified};
+
+pub(crate) use auth::Secret;
+pub use bootstrap::JoinError;
 
 use fnv::FnvHasher;
 use futures::future::TryFutureExt;
 use log::{error, info, warn};
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::{
-    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
-    convert::{TryFrom, TryInto},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    convert::TryInto,
     future::Future,
     hash::{Hash, Hasher},
     mem,
     net::SocketAddr,
+    path::PathBuf,
     pin::Pin,
-    sync::{Arc, Weak},
+    sync::{atomic::AtomicBool, Arc, Mutex, Weak},
     task::{Context, Poll},
     time::Duration,
 };
 use thiserror::Error;
 use tokio::{
-    sync::{broadcast, oneshot, RwLock},
+    sync::{broadcast, mpsc, oneshot, watch, RwLock},
     task,
-    time::sleep,
+    time::{sleep, Instant},
 };
 use tonic::{
-    transport::{self, ClientTlsConfig},
+    transport::ClientTlsConfig,
     Code, Request, Response, Status,
 };
 
 pub(crate) struct Config {
     pub lh: (usize, usize),
     pub k: usize,
-    pub seed: Option<Endpoint>,
+    pub seeds: Vec<Endpoint>,
+    pub shuffle_seeds: bool,
+    pub bootstrap_expect: Option<usize>,
+    pub bootstrap_peers: Vec<Endpoint>,
+    pub merge_interval: Option<Duration>,
+    pub merge_peers: Vec<Endpoint>,
     pub meta: Metadata,
     pub server_tls: bool,
     pub client_tls: Option<Arc<ClientTlsConfig>>,
     pub fd_timeout: Duration,
     pub fd_strikes: usize,
+    pub fd: Option<Box<dyn FaultDetector>>,
+    pub indirect_probes: usize,
+    pub admission: Option<Box<dyn Admission>>,
+    pub cluster_name: String,
+    pub persist: Option<PathBuf>,
+    pub static_recovery: bool,
+    pub metrics: Metrics,
+    pub admin: bool,
+    pub timings: ProtocolTimings,
+    pub zone_key: Option<String>,
+    pub secret: Option<Secret>,
+    pub advertise: Option<SocketAddr>,
 }
 
 type Grpc<T> = Result<T, Status>;
 type GrpcResponse<T> = Grpc<Response<T>>;
 
+/// The number of past configuration ids remembered by each member.
+const CONF_HISTORY: usize = 32;
+
 pub(crate) struct Cluster {
     cfg: Config,
     addr: SocketAddr,
     state: Arc<RwLock<State>>,
     cuts: broadcast::Sender<MultiNodeCut>,
+    suspicions: watch::Sender<Arc<[Suspicion]>>,
+    // held so that publishing suspicions never fails for lack of receivers.
+    suspicions_rx: watch::Receiver<Arc<[Suspicion]>>,
+    leaving: AtomicBool,
+    merging: AtomicBool,
+    fd: Mutex<Box<dyn FaultDetector>>,
+    transport: Arc<dyn Transport>,
+    admit: mpsc::UnboundedSender<Admit>,
+    // taken by whichever future is admitting requests (see Cluster::admit_requests).
+    admit_rx: Mutex<Option<mpsc::UnboundedReceiver<Admit>>>,
 }
 
 #[crate::async_trait]
@@ -81,8 +129,10 @@ impl Membership for Arc<Cluster> {
     /// The only thing we need to do is inform the joiner about which nodes to contact in
     /// order to initiate phase 2 of the join protocol.
     async fn pre_join(&self, req: Request<PreJoinReq>) -> GrpcResponse<PreJoinResp> {
-        let PreJoinReq { sender, uuid } = req.into_inner();
+        let PreJoinReq { sender, uuid, cluster_name, cd_config } = req.into_inner();
         sender.validate()?;
+        self.verify_cluster_name(&cluster_name)?;
+        self.verify_cd_config(&cd_config)?;
 
         let state = self.state.read().await;
 
@@ -103,11 +153,20 @@ impl Membership for Arc<Cluster> {
     /// The sender has (presumably) already performed a pre-join request, and received a
     /// list of observers to contact (of which we are ~one (presumably)).
     ///
-    /// Our role here is to notify the rest of the cluster that sender wants to join.
+    /// Our role here is to notify the rest of the cluster that sender wants to join, if the
+    /// configured admission hook (if any) allows it.
     async fn join(&self, req: Request<JoinReq>) -> GrpcResponse<JoinResp> {
+        let peer_certs = req.peer_certs();
         #[rustfmt::skip]
-        let JoinReq { sender, ring, uuid, conf_id, meta } = req.into_inner();
+        let JoinReq { sender, ring, uuid, conf_id, meta, cluster_name } = req.into_inner();
         sender.validate()?;
+        self.verify_cluster_name(&cluster_name)?;
+
+        if let Some(admission) = self.cfg.admission.as_ref() {
+            let joiner = Joiner::new(&sender, &uuid, &meta, peer_certs);
+            (admission.admit(&joiner).await).map_err(Status::permission_denied)?;
+        }
+
         let mut state = self.state.write().await;
 
         state.verify_config(conf_id)?;
@@ -121,6 +180,7 @@ impl Membership for Arc<Cluster> {
                 node: sender.clone(),
                 ring,
                 join: Some(Join { uuid, meta }),
+                meta: None,
             },
         );
 
@@ -156,11 +216,13 @@ impl Membership for Arc<Cluster> {
         }
 
         (edges.iter()).try_for_each(|e| state.verify_edge(&sender, e))?;
+        self.cfg.metrics.alerts_received().add(edges.len() as u64);
 
-        for Edge { node, ring, join } in edges {
+        for Edge { node, ring, join, meta } in edges {
             state.merge_cd_alert(
                 node,
                 join,
+                meta,
                 Vote {
                     node: sender.clone(),
                     ring,
@@ -214,6 +276,7 @@ impl Membership for Arc<Cluster> {
 
         if votes >= state.fast_quorum() {
             self.apply_view_change(&mut state, nodes);
+            self.cfg.metrics.fast_rounds().inc();
 
             info!(
                 "(fast) applied view-change with {} vote(s): conf_id={}",
@@ -237,10 +300,6 @@ impl Membership for Arc<Cluster> {
             return Err(Status::aborted("rank is too low"));
         }
 
-        let sender = self
-            .resolve_endpoint(&sender)
-            .map_err(|e| Status::invalid_argument(e.to_string()))?;
-
         state.px_rnd = rank.clone();
 
         let p = PromiseReq {
@@ -251,10 +310,10 @@ impl Membership for Arc<Cluster> {
             vval: state.px_vval.clone(),
         };
 
+        let transport = Arc::clone(&self.transport);
         task::spawn(async move {
-            MembershipClient::connect(sender)
-                .map_err(|e| Status::unavailable(e.to_string()))
-                .and_then(|mut c| async move { c.promise(p).await })
+            (transport.connect(&sender))
+                .and_then(|c| async move { c.promise(Request::new(p)).await })
                 .map_err(|e| warn!("promise failed: {}", e))
                 .await
         });
@@ -351,6 +410,7 @@ impl Membership for Arc<Cluster> {
         if votes > state.slow_quorum() {
             let (_, proposal) = state.px_accepted.remove(&rnd).unwrap();
             self.apply_view_change(&mut state, proposal);
+            self.cfg.metrics.slow_rounds().inc();
 
             info!(
                 "(slow) applied view-change with {} vote(s): conf_id={}",
@@ -379,7 +439,8 @@ impl Membership for Arc<Cluster> {
     /// of delivery at all healthy members.
     async fn broadcast(&self, req: Request<BroadcastReq>) -> GrpcResponse<Ack> {
         #[rustfmt::skip]
-        let BroadcastReq { unix, uniq, broadcasted } = req.into_inner();
+        let BroadcastReq { unix, uniq, broadcasted, cluster_name } = req.into_inner();
+        self.verify_cluster_name(&cluster_name)?;
 
         let broadcasted = broadcasted //
             .ok_or_else(|| Status::invalid_argument("missing oneof"))?;
@@ -389,6 +450,7 @@ impl Membership for Arc<Cluster> {
             let mut state = self.state.write().await;
 
             if !state.bcast_filter.insert(event_id) {
+                self.cfg.metrics.broadcast_duplicates().inc();
                 return Err(Status::already_exists("delivery is redundant"));
             }
 
@@ -400,22 +462,20 @@ impl Membership for Arc<Cluster> {
 
         subjects.sort();
         subjects.dedup();
+        self.cfg.metrics.broadcast_fanout().add(subjects.len() as u64);
 
         for subject in subjects {
             let req = Request::new(BroadcastReq {
                 unix,
                 uniq,
                 broadcasted: Some(broadcasted.clone()),
+                cluster_name: cluster_name.clone(),
             });
 
-            let subject = self
-                .resolve_endpoint(&subject)
-                .expect("all stored endpoints are valid");
-
+            let transport = Arc::clone(&self.transport);
             task::spawn(async move {
-                MembershipClient::connect(subject)
-                    .map_err(|e| Status::unavailable(e.to_string()))
-                    .and_then(|mut c| async move { c.broadcast(req).await })
+                (transport.connect(&subject))
+                    .and_then(|c| async move { c.broadcast(req).await })
                     .map_err(|e| match (e.code(), e.message()) {
                         (Code::AlreadyExists, "delivery is redundant") => {}
                         _ => warn!("infection failed: {}", e),
@@ -435,34 +495,164 @@ impl Membership for Arc<Cluster> {
     }
 
     /// Handle a fault-detection probe message.
-    async fn probe(&self, _: Request<Ack>) -> GrpcResponse<Ack> {
-        let State { ref nodes, .. } = *self.state.read().await;
+    ///
+    /// The response includes our configuration id, which allows the observer to find out
+    /// if it missed a view-change that we accepted.
+    async fn probe(&self, _: Request<Ack>) -> GrpcResponse<ProbeResp> {
+        let State { ref nodes, conf_id, .. } = *self.state.read().await;
 
         if !nodes.contains(&self.local_node()) {
             return Err(Status::unavailable("degraded"));
         }
 
+        Ok(Response::new(ProbeResp { conf_id }))
+    }
+
+    /// Handle a request to probe a subject on behalf of one of its observers.
+    ///
+    /// This allows observers to distinguish a faulty subject from a faulty link between the
+    /// observer and subject, before counting a probe as failed.
+    async fn probe_via(&self, req: Request<ProbeViaReq>) -> GrpcResponse<Ack> {
+        let ProbeViaReq { subject } = req.into_inner();
+
+        {
+            let State { ref nodes, .. } = *self.state.read().await;
+
+            if !nodes.contains(&self.local_node()) {
+                return Err(Status::unavailable("degraded"));
+            }
+            if !nodes.contains(&subject) {
+                return Err(Status::invalid_argument("subject is not a member"));
+            }
+        }
+
+        match self.probe_direct(&subject).await {
+            Some(_) => Ok(Response::new(Ack {})),
+            None => Err(Status::unavailable("subject did not respond")),
+        }
+    }
+
+    /// Handle a leave request from a member that is shutting down.
+    ///
+    /// We raise a down alert on every ring where we observe the sender, which allows it to
+    /// be removed from the configuration without waiting on the fault detector.
+    async fn leave(&self, req: Request<LeaveReq>) -> GrpcResponse<Ack> {
+        let LeaveReq { sender, conf_id } = req.into_inner();
+        let mut state = self.state.write().await;
+
+        state.verify_sender(&sender)?;
+        state.verify_config(conf_id)?;
+
+        let edges: Vec<_> = (state.observed_rings(&self.local_node(), &sender))
+            .map(|ring| Edge::down(sender.clone(), ring))
+            .collect();
+
+        if edges.is_empty() {
+            return Err(Status::invalid_argument("not an observer of sender"));
+        }
+
+        self.enqueue_edges(&mut state, edges);
+
         Ok(Response::new(Ack {}))
     }
+
+    /// Handle a metadata update request from a member.
+    ///
+    /// Much like a leave request, we raise an alert on every ring where we observe the
+    /// sender. Once enough observers agree, the update is applied as part of a regular
+    /// view-change.
+    async fn update_metadata(&self, req: Request<UpdateMetadataReq>) -> GrpcResponse<Ack> {
+        #[rustfmt::skip]
+        let UpdateMetadataReq { sender, conf_id, meta } = req.into_inner();
+        let mut state = self.state.write().await;
+
+        state.verify_sender(&sender)?;
+        state.verify_config(conf_id)?;
+
+        let edges: Vec<_> = (state.observed_rings(&self.local_node(), &sender))
+            .map(|ring| Edge::update(sender.clone(), ring, meta.clone()))
+            .collect();
+
+        if edges.is_empty() {
+            return Err(Status::invalid_argument("not an observer of sender"));
+        }
+
+        self.enqueue_edges(&mut state, edges);
+
+        Ok(Response::new(Ack {}))
+    }
+
+    /// Handle a discovery request from a node that is looking for other members of the mesh.
+    ///
+    /// We respond with the configuration we're a member of (if any), which lets the sender
+    /// decide whether to join it. Otherwise, we respond with the candidates we've discovered
+    /// for bootstrapping a new one, which lets the sender check that we agree on them.
+    async fn discover(&self, req: Request<DiscoverReq>) -> GrpcResponse<DiscoverResp> {
+        let DiscoverReq { sender, cluster_name } = req.into_inner();
+        sender.validate()?;
+        self.verify_cluster_name(&cluster_name)?;
+
+        #[rustfmt::skip]
+        let State { ref nodes, conf_id, ref bootstrap_candidates, .. } = *self.state.read().await;
+
+        let (members, candidates) = match nodes.contains(&self.local_node()) {
+            true => (nodes.len() as u64, Vec::new()),
+            false => (0, bootstrap_candidates.clone()),
+        };
+
+        let resp = DiscoverResp {
+            sender: self.local_node(),
+            conf_id,
+            members,
+            candidates,
+        };
+
+        Ok(Response::new(resp))
+    }
+
+    /// Handle a request for the active configuration from a member that missed a
+    /// view-change.
+    async fn fetch_config(&self, req: Request<FetchConfigReq>) -> GrpcResponse<JoinResp> {
+        let FetchConfigReq { sender, cluster_name } = req.into_inner();
+        sender.validate()?;
+        self.verify_cluster_name(&cluster_name)?;
+
+        let state = self.state.read().await;
+
+        if !state.nodes.contains(&self.local_node()) {
+            return Err(Status::unavailable("degraded"));
+        }
+
+        Ok(Response::new(state.join_response(self.local_node())))
+    }
 }
 
 impl Cluster {
-    pub(crate) fn new(cfg: Config, addr: SocketAddr) -> Self {
+    pub(crate) fn new(mut cfg: Config, addr: SocketAddr, transport: Arc<dyn Transport>) -> Self {
+        let fd = (cfg.fd.take()).unwrap_or_else(|| Box::new(StrikeDetector::new(cfg.fd_strikes)));
+
         let state = Arc::new(RwLock::new(State {
             uuid: NodeId::generate(),
+            meta: cfg.meta.clone(),
+            cluster_name: cfg.cluster_name.as_str().into(),
+            zone_key: cfg.zone_key.clone(),
             conf_id: 0,
+            conf_history: VecDeque::with_capacity(CONF_HISTORY),
             nodes: Tumbler::new(cfg.k),
             uuids: BTreeSet::new(),
             metadata: HashMap::new(),
             last_cut: None,
 
-            bcast_filter: EventFilter::new(Duration::from_secs(3600)),
+            bcast_filter: EventFilter::new(cfg.timings.broadcast_window),
 
             join_requests: HashMap::new(),
+            bootstrap_candidates: Vec::new(),
 
             cd_batch: AlertBatch::default(),
             cd_joiners: HashMap::new(),
+            cd_updates: HashMap::new(),
             cd_reports: BTreeMap::new(),
+            cd_started: None,
 
             fpx_announced: false,
             fpx_voters: HashSet::new(),
@@ -478,18 +668,33 @@ impl Cluster {
         }));
 
         let (cuts, _) = broadcast::channel(8);
+        let (suspicions, suspicions_rx) = watch::channel(Vec::new().into());
+        let (admit, admit_rx) = mpsc::unbounded_channel();
+
+        let transport = match cfg.secret.clone() {
+            Some(secret) => Arc::new(Authenticated::new(transport, secret)),
+            None => transport,
+        };
 
         Cluster {
             cfg,
             addr,
             state,
             cuts,
+            suspicions,
+            suspicions_rx,
+            leaving: AtomicBool::new(false),
+            merging: AtomicBool::new(false),
+            fd: Mutex::new(fd),
+            transport,
+            admit,
+            admit_rx: Mutex::new(Some(admit_rx)),
         }
     }
 
     #[inline]
-    pub(crate) fn into_service(self: Arc<Self>) -> MembershipServer<Arc<Self>> {
-        MembershipServer::new(self)
+    pub(crate) fn into_service(self: Arc<Self>) -> MembershipServer<Verified> {
+        MembershipServer::new(Verified(self))
     }
 
     pub(crate) fn subscribe(&self) -> Subscription {
@@ -498,48 +703,93 @@ impl Cluster {
         Subscription::new(state, rx)
     }
 
+    /// Watch the subjects that the local fault detector currently suspects.
+    pub(crate) fn suspicions(&self) -> watch::Receiver<Arc<[Suspicion]>> {
+        self.suspicions_rx.clone()
+    }
+
+    /// Returns the address the local node is reachable at, which identifies it in the mesh.
+    #[inline]
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the last accepted view-change proposal, if there is one.
+    pub(crate) async fn last_cut(&self) -> Option<MultiNodeCut> {
+        self.state.read().await.last_cut.clone()
+    }
+
     #[inline]
     fn local_node(&self) -> Endpoint {
         Endpoint::from(self.addr).tls(self.cfg.server_tls)
     }
 
-    /// Resolve an `Endpoint` to a `transport::Endpoint`, applying the configured client TLS
-    /// settings if specified by the endpoint.
-    fn resolve_endpoint(&self, e: &Endpoint) -> Result<transport::Endpoint, EndpointError> {
-        if !e.tls {
-            return e.try_into();
+    /// Verify that a request originated from a member of a cluster with the same name as
+    /// ours.
+    fn verify_cluster_name(&self, name: &str) -> Grpc<()> {
+        err_when(self.cfg.cluster_name != name, || {
+            Status::failed_precondition(format!(
+                "cluster name mismatch: expected {:?}, got {:?}",
+                self.cfg.cluster_name, name
+            ))
+        })
+    }
+
+    /// Returns the local cut detector configuration.
+    fn cd_config(&self) -> CdConfig {
+        CdConfig {
+            subjects: self.cfg.k as u64,
+            stable: self.cfg.lh.1 as u64,
+            unstable: self.cfg.lh.0 as u64,
         }
+    }
 
-        let tls = (self.cfg.client_tls)
-            .as_deref()
-            .cloned()
-            .unwrap_or_else(ClientTlsConfig::new);
+    /// Verify that a joiner was configured with the same cut detector thresholds as ours.
+    fn verify_cd_config(&self, cd: &CdConfig) -> Grpc<()> {
+        let local = self.cd_config();
+        err_when(local != *cd, || {
+            Status::failed_precondition(format!(
+                "cut detector config mismatch: expected {:?}, got {:?}",
+                local, cd
+            ))
+        })
+    }
 
-        Ok(transport::Endpoint::try_from(e)?.tls_config(tls)?)
+    /// Connect to the member listening at `e`.
+    async fn connect(&self, e: &Endpoint) -> Grpc<Client> {
+        self.transport.connect(e).await
     }
 
     /// Apply a view-change proposal to `state`. This will propagate the view-change to any
     /// subscribed tasks, and unblock any joining nodes in the proposal (if we're the ones
     /// handling their join request).
-    fn apply_view_change(&self, state: &mut State, proposal: Vec<Endpoint>) {
+    fn apply_view_change(&self, state: &mut State, proposal: Vec<Change>) {
         let mut joined = Vec::with_capacity(proposal.len());
+        let mut updated = Vec::with_capacity(proposal.len());
         let mut kicked = Vec::with_capacity(proposal.len());
 
-        for node in proposal {
+        for Change { node, meta } in proposal {
             if let Some(Join { uuid, meta }) = state.cd_joiners.remove(&node) {
                 joined.push(self.resolve_member_meta(meta.clone(), &node).unwrap());
                 state.join_node(node, Join { uuid, meta });
+            } else if let Some(meta) = meta {
+                updated.push(self.resolve_member_meta(meta.clone(), &node).unwrap());
+                state.update_node(&node, meta);
             } else {
                 let meta = state.kick_node(&node);
                 kicked.push(self.resolve_member_meta(meta, &node).unwrap());
             }
         }
 
+        if let Some(started) = state.cd_started {
+            self.cfg.metrics.view_change_latency().observe(started.elapsed());
+        }
         state.clear_consensus();
 
         let local_node = self.local_node();
 
         joined.sort_by_key(|m| m.addr());
+        updated.sort_by_key(|m| m.addr());
         kicked.sort_by_key(|m| m.addr());
 
         let mut members: Vec<_> = (state.nodes.iter())
@@ -551,10 +801,13 @@ impl Cluster {
         let cut = MultiNodeCut {
             skipped: 0,
             local_addr: self.addr,
+            cluster_name: state.cluster_name.clone(),
             degraded: !state.nodes.contains(&local_node),
+            merged: false,
             conf_id: state.rehash_config(),
             members: members.into(),
             joined: joined.into(),
+            updated: updated.into(),
             kicked: kicked.into(),
         };
 
@@ -598,10 +851,13 @@ impl Cluster {
         Some(tls)
     }
 
-    /// Propagate a view-change proposal to all subscribed tasks. This includes the fault
-    /// detector, partition detector, and any subscribed mesh services (from the overlay).
+    /// Propagate a view-change proposal to the transport and all subscribed tasks. This
+    /// includes the fault detector, partition detector, and any subscribed mesh services
+    /// (from the overlay).
     #[inline]
     fn propagate_cut(&self, cut: MultiNodeCut) {
+        self.transport.sync(&cut);
+
         // NOTE: this might fail, but if it does we'll exit soon anyway.
         let _ = self.cuts.send(cut);
     }
@@ -636,7 +892,7 @@ impl Cluster {
     /// same ~short period of time into the same batch.
     async fn send_batch(self: Arc<Self>) {
         // wait a bit to allow more edges to be included in this batch.
-        sleep(Duration::from_millis(100)).await;
+        sleep(self.cfg.timings.alert_batch).await;
 
         let sender = self.local_node();
 
@@ -650,6 +906,7 @@ impl Cluster {
         if edges.is_empty() {
             return;
         }
+        self.cfg.metrics.alerts_sent().add(edges.len() as u64);
 
         self.do_broadcast(BatchedAlert(BatchedAlertReq {
             sender,
@@ -666,6 +923,7 @@ impl Cluster {
             unix: id.timestamp(),
             uniq: id.unique(),
             broadcasted: Some(msg),
+            cluster_name: self.cfg.cluster_name.clone(),
         });
 
         if let Err(e) = self.broadcast(req).await {
@@ -681,7 +939,7 @@ impl Cluster {
         }
 
         #[rustfmt::skip]
-        let PaxosRound { sender, conf_id, .. } = px.init_delay().await;
+        let PaxosRound { sender, conf_id, .. } = px.init_delay(&self.cfg.timings).await;
 
         let rank = {
             let mut state = self.state.write().await;
@@ -718,10 +976,10 @@ struct PaxosRound {
 }
 
 impl PaxosRound {
-    async fn init_delay(self) -> Self {
-        let exp = ((self.members + 1) as f64).log(2.0) * 4000.0;
-        let ms = thread_rng().gen_range(1000..exp as u64);
-        sleep(Duration::from_millis(ms)).await;
+    async fn init_delay(self, t: &ProtocolTimings) -> Self {
+        let max = t.px_delay_scale.mul_f64(((self.members + 1) as f64).log(2.0));
+        let delay = local_rng().gen_range(t.px_delay_min..max);
+        sleep(delay).await;
         self
     }
 }
@@ -803,7 +1061,11 @@ struct Vote {
 pub(crate) struct State {
     // membership state
     uuid: NodeId,
+    meta: Metadata,
+    cluster_name: Arc<str>,
+    zone_key: Option<String>,
     conf_id: u64,
+    conf_history: VecDeque<u64>,
     nodes: Tumbler<Endpoint>,
     uuids: BTreeSet<NodeId>,
     metadata: HashMap<Endpoint, Metadata>,
@@ -814,24 +1076,27 @@ pub(crate) struct State {
 
     // join protocol state
     join_requests: HashMap<Endpoint, PendingJoin>,
+    bootstrap_candidates: Vec<Endpoint>,
 
     // cut detection state
     cd_batch: AlertBatch,
     cd_joiners: HashMap<Endpoint, Join>,
+    cd_updates: HashMap<Endpoint, Option<Metadata>>,
     cd_reports: BTreeMap<Endpoint, HashSet<Vote>>,
+    cd_started: Option<Instant>,
 
     // fast paxos state
     fpx_announced: bool,
     fpx_voters: HashSet<Endpoint>,
-    fpx_ballots: FreqSet<Vec<Endpoint>>,
+    fpx_ballots: FreqSet<Vec<Change>>,
 
     // classical paxos state
     px_rnd: Rank,
     px_vrnd: Rank,
     px_crnd: Rank,
-    px_vval: Vec<Endpoint>,
-    px_cval: Vec<Endpoint>,
-    px_accepted: HashMap<Rank, (HashSet<Endpoint>, Vec<Endpoint>)>,
+    px_vval: Vec<Change>,
+    px_cval: Vec<Change>,
+    px_accepted: HashMap<Rank, (HashSet<Endpoint>, Vec<Change>)>,
     px_promised: Vec<PromiseReq>,
 }
 
@@ -871,6 +1136,18 @@ impl State {
         )
     }
 
+    /// Returns an iterator over every ring on which `observer` observes `subject`.
+    fn observed_rings<'a>(
+        &'a self,
+        observer: &'a Endpoint,
+        subject: &'a Endpoint,
+    ) -> impl Iterator<Item = u64> + 'a {
+        (self.nodes.predecessors(subject))
+            .enumerate()
+            .filter(move |(_, e)| *e == observer)
+            .map(|(ring, _)| ring as u64)
+    }
+
     /// Verify that `sender` is a member of the active configuration.
     fn verify_sender(&self, sender: &Endpoint) -> Grpc<()> {
         err_when(!self.nodes.contains(sender), || {
@@ -901,11 +1178,32 @@ impl State {
     }
 
     /// Merge a cut detection alert into the local state.
-    fn merge_cd_alert(&mut self, node: Endpoint, join: Option<Join>, src: Vote) {
+    fn merge_cd_alert(
+        &mut self,
+        node: Endpoint,
+        join: Option<Join>,
+        meta: Option<Metadata>,
+        src: Vote,
+    ) {
         if let Some(join) = join {
             self.cd_joiners.insert(node.clone(), join);
+        } else if let Some(meta) = meta {
+            // a pending update may be replaced by a newer one, but never by a down alert.
+            match self.cd_updates.entry(node.clone()) {
+                Entry::Occupied(mut o) if o.get().is_some() => {
+                    o.insert(Some(meta));
+                }
+                Entry::Occupied(_) => {}
+                Entry::Vacant(v) => {
+                    v.insert(Some(meta));
+                }
+            }
+        } else {
+            // down alerts take precedence over metadata updates for the same node.
+            self.cd_updates.insert(node.clone(), None);
         }
         self.cd_reports.entry(node).or_default().insert(src);
+        self.cd_started.get_or_insert_with(Instant::now);
     }
 
     /// Merge implicit cut detection alerts into the local state.
@@ -943,8 +1241,11 @@ impl State {
 
     /// Attempt to generate a view-change proposal for this cut detection round.
     ///
+    /// Metadata updates carry the new metadata in the proposal itself, so that every member
+    /// applies the same update no matter which alerts it received.
+    ///
     /// If this returns `Some(_)`, any pending edge reports will have been removed.
-    fn generate_cd_proposal(&mut self, (l, h): (usize, usize)) -> Option<Vec<Endpoint>> {
+    fn generate_cd_proposal(&mut self, (l, h): (usize, usize)) -> Option<Vec<Change>> {
         // if any reported edges are in unstable report mode, bail.
         guard! { self.cd_report_counts().all(|n| n >= h || n < l) }
         // if there isn't at least one edge in stable report mode, bail.
@@ -952,7 +1253,14 @@ impl State {
         // if we've already announced a proposal round, bail.
         guard! { !mem::replace(&mut self.fpx_announced, true) }
 
-        Some(self.drain_cd_reports_gte(h).collect())
+        let proposal = (self.drain_cd_reports_gte(h))
+            .map(|node| {
+                let meta = self.cd_updates.get(&node).cloned().flatten();
+                Change::new(node, meta)
+            })
+            .collect();
+
+        Some(proposal)
     }
 
     /// Returns an iterator over the number of unique reports received for each edge in
@@ -973,7 +1281,7 @@ impl State {
     }
 
     /// Register the initiation of a fast paxos round.
-    fn register_fpx_round(&mut self, proposal: &[Endpoint]) {
+    fn register_fpx_round(&mut self, proposal: &[Change]) {
         if self.px_rnd.round >= 2 {
             return;
         }
@@ -994,7 +1302,9 @@ impl State {
     fn clear_consensus(&mut self) {
         // clear cut detection state
         self.cd_joiners.clear();
+        self.cd_updates.clear();
         self.cd_reports.clear();
+        self.cd_started = None;
 
         // clear fast paxos state
         self.fpx_announced = false;
@@ -1021,11 +1331,45 @@ impl State {
 
     /// Add `node` to the active configuration.
     fn join_node(&mut self, node: Endpoint, Join { uuid, meta }: Join) {
-        assert!(self.nodes.insert(node.clone()));
         assert!(self.uuids.insert(uuid));
+        self.insert_node(node, meta);
+    }
+
+    /// Returns the zone named by `meta`, if a zone key is configured.
+    fn zone_of(&self, meta: &Metadata) -> Option<u64> {
+        (self.zone_key.as_ref())
+            .and_then(|key| meta.get(key))
+            .map(|zone| {
+                let mut h = FnvHasher::default();
+                zone.hash(&mut h);
+                h.finish()
+            })
+    }
+
+    /// Insert `node` into the rings of the active configuration, placing it in the zone named
+    /// by its metadata (if a zone key is configured).
+    fn insert_node(&mut self, node: Endpoint, meta: Metadata) {
+        match self.zone_of(&meta) {
+            Some(zone) => assert!(self.nodes.insert_in_zone(node.clone(), zone)),
+            None => assert!(self.nodes.insert(node.clone())),
+        }
         assert!(self.metadata.insert(node, meta).is_none());
     }
 
+    /// Replace the metadata of `node`, which must be in the active configuration.
+    ///
+    /// If its zone changed, `node` is placed again, so that its edges are the same as those
+    /// of a member that inserted it with the updated metadata.
+    fn update_node(&mut self, node: &Endpoint, meta: Metadata) {
+        if self.zone_of(&self.metadata[node]) == self.zone_of(&meta) {
+            *self.metadata.get_mut(node).unwrap() = meta;
+            return;
+        }
+
+        self.kick_node(node);
+        self.insert_node(node.clone(), meta);
+    }
+
     /// Remove `node` from the active configuration.
     fn kick_node(&mut self, node: &Endpoint) -> Metadata {
         assert!(self.nodes.remove(node));
@@ -1036,13 +1380,29 @@ impl State {
     fn rehash_config(&mut self) -> u64 {
         let mut h = FnvHasher::default();
 
+        self.cluster_name.hash(&mut h);
         self.nodes.iter().for_each(|e| e.hash(&mut h));
         self.uuids.iter().for_each(|i| i.hash(&mut h));
 
+        // metadata is included so that updates result in a new configuration.
+        self.nodes.iter().for_each(|e| self.metadata[e].hash(&mut h));
+
         self.conf_id = h.finish();
+
+        if self.conf_history.len() == CONF_HISTORY {
+            self.conf_history.pop_front();
+        }
+        self.conf_history.push_back(self.conf_id);
+
         self.conf_id
     }
 
+    /// Returns true if `conf_id` is the active configuration, or one of the last few that
+    /// preceded it.
+    fn has_seen_config(&self, conf_id: u64) -> bool {
+        self.conf_history.contains(&conf_id)
+    }
+
     /// Respond to any inflight join calls.
     fn respond_to_joiners(&mut self, cut: &MultiNodeCut, sender: Endpoint) {
         let resp = self.join_response(sender);
@@ -1075,7 +1435,7 @@ impl State {
     }
 
     // TODO(doc)
-    fn choose_px_proposal(&self) -> Option<Vec<Endpoint>> {
+    fn choose_px_proposal(&self) -> Option<Vec<Change>> {
         // NOTE(invariant): there must be at least one promise request available
         assert!(!self.px_promised.is_empty());
 
@@ -1083,7 +1443,7 @@ impl State {
 
         // Let k be the largest value of vr(a) for all a in Q.
         //     V be the set of all vv(a) for all a in Q s.t vr(a) == k
-        let vvals: FreqSet<&[Endpoint]> = self
+        let vvals: FreqSet<&[Change]> = self
             .px_promised
             .iter()
             .filter(|p| &p.vrnd == max_vrnd)
@@ -0,0 +1,120 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Persistence of the last accepted configuration, so that members can find their way back
+//! to the mesh after a restart.
+use super::{
+    cut::{self, Subscription},
+    proto::{NodeMetadata, Snapshot},
+    Cluster, State,
+};
+use futures::future::pending;
+use log::warn;
+use prost::Message;
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task;
+
+impl Cluster {
+    /// Write a snapshot of the active configuration to the configured path (if any) after
+    /// every accepted view-change. Failed writes are logged, and retried on the next one.
+    pub(crate) async fn persist_cuts(self: Arc<Self>, mut cuts: Subscription) -> cut::Result {
+        let path = match self.cfg.persist.as_ref() {
+            Some(path) => path,
+            None => return pending().await,
+        };
+
+        loop {
+            cuts.recv().await?;
+
+            let snapshot = self.state.read().await.snapshot();
+            let path = path.clone();
+
+            if let Err(e) = blocking(move || write_snapshot(&path, &snapshot)).await {
+                warn!("failed to persist snapshot: {}", e);
+            }
+        }
+    }
+
+    /// Load the snapshot persisted by a previous run, if there is one and it belongs to a
+    /// cluster with the same name as ours.
+    pub(super) async fn load_snapshot(&self) -> Option<Snapshot> {
+        let path = self.cfg.persist.clone()?;
+
+        let snapshot = match blocking(move || read_snapshot(&path)).await {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("failed to load snapshot: {}", e);
+                return None;
+            }
+        };
+
+        if snapshot.cluster_name != self.cfg.cluster_name {
+            warn!(
+                "ignoring snapshot: cluster name mismatch: expected {:?}, got {:?}",
+                self.cfg.cluster_name, snapshot.cluster_name
+            );
+            return None;
+        }
+
+        Some(snapshot)
+    }
+}
+
+impl State {
+    /// Returns a snapshot of the active configuration.
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cluster_name: self.cluster_name.to_string(),
+            conf_id: self.conf_id,
+            uuid: self.uuid.clone(),
+            nodes: (self.metadata.iter())
+                .map(|(node, meta)| NodeMetadata {
+                    node: node.clone(),
+                    meta: meta.clone(),
+                })
+                .collect(),
+            uuids: self.uuids.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Run a blocking filesystem operation without stalling the runtime.
+async fn blocking<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    (task::spawn_blocking(f).await).unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// Atomically replace the snapshot at `path`, such that a crash never leaves a partially
+/// written snapshot behind.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(snapshot.encoded_len());
+    snapshot.encode(&mut buf).expect("buffer has sufficient capacity");
+
+    let tmp = tmp_path(path);
+    fs::write(&tmp, &buf)?;
+    fs::rename(&tmp, path)
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Snapshot> {
+    let buf = fs::read(path)?;
+    Snapshot::decode(&*buf).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Returns the path a snapshot is written to before it replaces the one at `path`.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
@@ -5,7 +5,16 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 //! Protocol buffers definitions used in blip's membership protocol.
-use rand::random;
+use crate::rng::local_rng;
+use prost::{
+    bytes::{Buf, BufMut},
+    encoding::{
+        self, encode_key, encode_varint, encoded_len_varint, hash_map, key_len, skip_field,
+        string, DecodeContext, WireType,
+    },
+    DecodeError, Message,
+};
+use rand::Rng;
 use std::{
     cmp,
     collections::HashMap,
@@ -181,7 +190,88 @@ impl From<u128> for NodeId {
 impl NodeId {
     #[inline]
     pub fn generate() -> Self {
-        random::<u128>().into()
+        local_rng().gen::<u128>().into()
+    }
+}
+
+/// Metadata exposed by a node during the join protocol.
+///
+/// This is defined here rather than generated from `blip.proto` so that its keys are always
+/// encoded in the same order. Every member must encode a message to the same bytes for it to
+/// be authenticated.
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Metadata {
+    /// Any keys exposed by the node.
+    pub keys: HashMap<String, Vec<u8>>,
+}
+
+impl Metadata {
+    /// Returns every key and value, sorted by key.
+    pub fn sorted(&self) -> Vec<(&String, &Vec<u8>)> {
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort();
+        keys
+    }
+
+    /// Returns the encoded length of the map entry for `key` and `val`, without its tag.
+    fn entry_len(key: &str, val: &[u8]) -> usize {
+        let field = |len| match len {
+            0 => 0,
+            len => key_len(1) + encoded_len_varint(len as u64) + len,
+        };
+
+        field(key.len()) + field(val.len())
+    }
+}
+
+impl Message for Metadata {
+    /// Encode every key in sorted order. Keys and values equal to their default are omitted,
+    /// just as they would be in a generated map field.
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        for (key, val) in self.sorted() {
+            encode_key(1, WireType::LengthDelimited, buf);
+            encode_varint(Self::entry_len(key, val) as u64, buf);
+
+            if !key.is_empty() {
+                string::encode(1, key, buf);
+            }
+            if !val.is_empty() {
+                encoding::bytes::encode(2, val, buf);
+            }
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => hash_map::merge(string::merge, encoding::bytes::merge, &mut self.keys, buf, ctx),
+            _ => skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        (self.keys.iter())
+            .map(|(key, val)| Self::entry_len(key, val))
+            .map(|len| key_len(1) + encoded_len_varint(len as u64) + len)
+            .sum()
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+impl Eq for Metadata {}
+
+impl Hash for Metadata {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sorted().hash(state);
     }
 }
 
@@ -207,10 +297,36 @@ impl Edge {
             node,
             ring,
             join: None,
+            meta: None,
+        }
+    }
+
+    pub const fn update(node: Endpoint, ring: u64, meta: Metadata) -> Self {
+        Self {
+            node,
+            ring,
+            join: None,
+            meta: Some(meta),
         }
     }
 }
 
+impl Eq for Change {}
+
+impl Hash for Change {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.node.hash(state);
+        self.meta.hash(state);
+    }
+}
+
+impl Change {
+    pub const fn new(node: Endpoint, meta: Option<Metadata>) -> Self {
+        Self { node, meta }
+    }
+}
+
 impl Rank {
     pub const fn new(round: u32, node_idx: u64) -> Self {
         Self { round, node_idx }
@@ -0,0 +1,436 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Transports over which members of a mesh communicate.
+//!
+//! By default, members talk to each other over tcp with tonic. A [MemNetwork] can be used
+//! instead to run many members in the same process without any sockets, while dropping,
+//! delaying, or partitioning messages between chosen members.
+use super::{
+    auth::Secret,
+    cut::{self, MultiNodeCut},
+    proto::{membership_client::MembershipClient, membership_server::Membership, *},
+    Cluster, Grpc, GrpcResponse,
+};
+use crate::rng::local_rng;
+use futures::future::pending;
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{sync::oneshot, time::sleep};
+use tonic::{
+    transport::{self, Channel, ClientTlsConfig},
+    Code, Request, Status,
+};
+
+/// A connection to a remote member.
+pub(crate) type Client = Box<dyn Membership>;
+
+/// A request from a remote member, waiting to be admitted by the local member.
+pub(crate) type Admit = oneshot::Sender<()>;
+
+/// Pooled channels to other nodes, by the endpoint they connect to.
+type Pool = Arc<Mutex<HashMap<Endpoint, Channel>>>;
+
+/// A way of connecting to other members of the mesh.
+#[crate::async_trait]
+pub(crate) trait Transport: Send + Sync + 'static {
+    /// Connect to the member listening at `to`.
+    async fn connect(&self, to: &Endpoint) -> Grpc<Client>;
+
+    /// Inform the transport that `cut` was accepted, so that any connections it holds can
+    /// be kept in sync with the active configuration.
+    fn sync(&self, _cut: &MultiNodeCut) {}
+}
+
+/// A [Transport] that connects to members over tcp with tonic.
+///
+/// Channels are pooled and reused across messages. Members of the active configuration
+/// share the channels held by their [Member][super::cut::Member], while other nodes (like
+/// seeds and joiners) are pooled until the next view-change. A channel is evicted when a
+/// request sent over it fails to reach the remote node.
+pub(crate) struct Tonic {
+    tls: Option<Arc<ClientTlsConfig>>,
+    pool: Pool,
+}
+
+impl Tonic {
+    pub(crate) fn new(tls: Option<Arc<ClientTlsConfig>>) -> Self {
+        let pool = Arc::new(Mutex::new(HashMap::new()));
+        Self { tls, pool }
+    }
+
+    /// Returns the pooled channel to `e`, creating a lazily connected one if necessary.
+    fn channel(&self, e: &Endpoint) -> Result<Channel, EndpointError> {
+        if let Some(c) = self.pool.lock().unwrap().get(e) {
+            return Ok(c.clone());
+        }
+
+        let c = self.resolve_endpoint(e)?.connect_lazy()?;
+        Ok(self.pool.lock().unwrap().entry(e.clone()).or_insert(c).clone())
+    }
+
+    /// Resolve an `Endpoint` to a `transport::Endpoint`, applying the configured client TLS
+    /// settings if specified by the endpoint.
+    fn resolve_endpoint(&self, e: &Endpoint) -> Result<transport::Endpoint, EndpointError> {
+        if !e.tls {
+            return e.try_into();
+        }
+
+        let tls = (self.tls)
+            .as_deref()
+            .cloned()
+            .unwrap_or_else(ClientTlsConfig::new);
+
+        Ok(transport::Endpoint::try_from(e)?.tls_config(tls)?)
+    }
+}
+
+#[crate::async_trait]
+impl Transport for Tonic {
+    async fn connect(&self, to: &Endpoint) -> Grpc<Client> {
+        let c = (self.channel(to)).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let client = MembershipClient::new(c);
+        let pool = Arc::clone(&self.pool);
+        Ok(Box::new(Remote { client, pool, to: to.clone() }))
+    }
+
+    /// Evict channels to nodes that are no longer members, and pool channels to those that
+    /// just joined. Channels to members that remain are kept as-is.
+    fn sync(&self, cut: &MultiNodeCut) {
+        let members: HashSet<Endpoint> = cut.members().iter().map(Endpoint::from).collect();
+        let mut pool = self.pool.lock().unwrap();
+        pool.retain(|e, _| members.contains(e));
+
+        for m in cut.members().iter() {
+            pool.entry(m.into()).or_insert_with(|| m.channel());
+        }
+    }
+}
+
+/// A member reached over tcp.
+struct Remote {
+    client: MembershipClient<Channel>,
+    pool: Pool,
+    to: Endpoint,
+}
+
+impl Remote {
+    /// Evict the pooled channel to this member if `resp` failed to reach it, so that the
+    /// next connection to it starts from a fresh channel.
+    fn evict_on_error<T>(&self, resp: &GrpcResponse<T>) {
+        if matches!(resp, Err(s) if matches!(s.code(), Code::Unavailable | Code::Unknown)) {
+            self.pool.lock().unwrap().remove(&self.to);
+        }
+    }
+}
+
+/// A [Transport] that signs every request sent over its connections with a [Secret].
+pub(crate) struct Authenticated {
+    inner: Arc<dyn Transport>,
+    secret: Secret,
+}
+
+impl Authenticated {
+    pub(crate) fn new(inner: Arc<dyn Transport>, secret: Secret) -> Self {
+        Self { inner, secret }
+    }
+}
+
+#[crate::async_trait]
+impl Transport for Authenticated {
+    async fn connect(&self, to: &Endpoint) -> Grpc<Client> {
+        let inner = self.inner.connect(to).await?;
+        let secret = self.secret.clone();
+        Ok(Box::new(Signed { inner, secret }))
+    }
+
+    fn sync(&self, cut: &MultiNodeCut) {
+        self.inner.sync(cut);
+    }
+}
+
+/// A remote member that every request is signed for.
+struct Signed {
+    inner: Client,
+    secret: Secret,
+}
+
+/// The local member as served to remote ones, which verifies that requests were signed
+/// with the configured [Secret] (if any) before handling them.
+///
+/// Requests are only handled once admitted by [Cluster::admit_requests].
+pub(crate) struct Verified(pub(crate) Arc<Cluster>);
+
+impl Cluster {
+    /// Admit requests from remote members until the mesh is brought down.
+    ///
+    /// Connections accepted by the grpc server are driven separately from it, so requests
+    /// are only handled while this is being polled. Otherwise a mesh that has stalled (or
+    /// been dropped) would keep answering probes on behalf of the local member.
+    pub(crate) async fn admit_requests(self: Arc<Self>) -> cut::Result {
+        let rx = self.admit_rx.lock().unwrap().take();
+        if let Some(mut rx) = rx {
+            while let Some(tx) = rx.recv().await {
+                let _ = tx.send(());
+            }
+        }
+        pending().await
+    }
+
+    /// Wait until the request being handled is admitted by [Cluster::admit_requests].
+    async fn admitted(&self) -> Grpc<()> {
+        let (tx, rx) = oneshot::channel();
+        (self.admit.send(tx)).map_err(|_| Status::unavailable("mesh is not running"))?;
+        rx.await.map_err(|_| Status::unavailable("mesh is not running"))
+    }
+}
+
+/// A simulated network that members can communicate over without any sockets, and which
+/// can be made to misbehave between chosen members.
+///
+/// Members are added to the network with [Mesh::serve_in_memory][serve], and addressed by
+/// the (arbitrary) [SocketAddr] they were served on. Cloning this is cheap, and all clones
+/// refer to the same network.
+///
+/// [serve]: crate::Mesh::serve_in_memory
+#[derive(Clone, Default)]
+pub struct MemNetwork {
+    inner: Arc<Mutex<Net>>,
+}
+
+#[derive(Default)]
+struct Net {
+    // each member is registered along with the id of the transport it was served with.
+    nodes: HashMap<SocketAddr, (u64, Weak<Cluster>)>,
+    links: HashMap<(SocketAddr, SocketAddr), Link>,
+    next_id: u64,
+}
+
+/// Faults injected into messages sent from one member to another.
+#[derive(Copy, Clone, Default)]
+struct Link {
+    partitioned: bool,
+    drop_rate: f64,
+    delay: Duration,
+}
+
+impl MemNetwork {
+    /// Create a new, empty network.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Partition the network such that no messages can be exchanged between any member of
+    /// `a` and any member of `b`, in either direction.
+    pub fn partition(&self, a: &[SocketAddr], b: &[SocketAddr]) {
+        let mut net = self.inner.lock().unwrap();
+
+        for (x, y) in a.iter().flat_map(|x| b.iter().map(move |y| (*x, *y))) {
+            net.links.entry((x, y)).or_default().partitioned = true;
+            net.links.entry((y, x)).or_default().partitioned = true;
+        }
+    }
+
+    /// Drop a fraction (between 0 and 1) of the messages sent from `from` to `to`.
+    ///
+    /// # Panics
+    /// Panics if `rate` isn't between 0 and 1.
+    pub fn drop_rate(&self, from: SocketAddr, to: SocketAddr, rate: f64) {
+        assert!((0.0..=1.0).contains(&rate));
+        let mut net = self.inner.lock().unwrap();
+        net.links.entry((from, to)).or_default().drop_rate = rate;
+    }
+
+    /// Delay every message sent from `from` to `to` by `delay`.
+    pub fn delay(&self, from: SocketAddr, to: SocketAddr, delay: Duration) {
+        let mut net = self.inner.lock().unwrap();
+        net.links.entry((from, to)).or_default().delay = delay;
+    }
+
+    /// Remove all partitions, drops, and delays from the network.
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().links.clear();
+    }
+
+    pub(crate) fn register(&self, transport: &MemTransport, cluster: &Arc<Cluster>) {
+        let mut net = self.inner.lock().unwrap();
+        (net.nodes).insert(transport.addr, (transport.id, Arc::downgrade(cluster)));
+    }
+
+    pub(crate) fn deregister(&self, addr: SocketAddr) {
+        self.inner.lock().unwrap().nodes.remove(&addr);
+    }
+
+    /// Returns a [Transport] for the member at `addr`.
+    ///
+    /// Messages can only be sent with it while the member it's registered with is, so tasks
+    /// that outlive a crashed member can't keep taking part in the protocol.
+    pub(crate) fn transport(&self, addr: SocketAddr) -> MemTransport {
+        let mut net = self.inner.lock().unwrap();
+        net.next_id += 1;
+
+        MemTransport {
+            net: self.clone(),
+            addr,
+            id: net.next_id,
+        }
+    }
+
+    /// Returns whether the member at `addr` is registered with the transport `id`.
+    fn is_registered(&self, addr: SocketAddr, id: u64) -> bool {
+        matches!(self.inner.lock().unwrap().nodes.get(&addr), Some((i, _)) if *i == id)
+    }
+
+    /// Send a message from `from` to `to`, applying any faults on the link between them.
+    async fn traverse(&self, from: SocketAddr, to: SocketAddr) -> Grpc<()> {
+        let link = (self.inner.lock().unwrap().links)
+            .get(&(from, to))
+            .copied()
+            .unwrap_or_default();
+
+        if link.partitioned {
+            return Err(Status::unavailable("network partitioned"));
+        }
+        if link.drop_rate > 0.0 && local_rng().gen_bool(link.drop_rate) {
+            return Err(Status::unavailable("message dropped"));
+        }
+        if link.delay > Duration::from_secs(0) {
+            sleep(link.delay).await;
+        }
+
+        Ok(())
+    }
+
+    fn lookup(&self, addr: SocketAddr) -> Option<Arc<Cluster>> {
+        (self.inner.lock().unwrap().nodes.get(&addr)).and_then(|(_, c)| c.upgrade())
+    }
+}
+
+/// A [Transport] that connects to members of a [MemNetwork].
+pub(crate) struct MemTransport {
+    net: MemNetwork,
+    addr: SocketAddr,
+    id: u64,
+}
+
+#[crate::async_trait]
+impl Transport for MemTransport {
+    async fn connect(&self, to: &Endpoint) -> Grpc<Client> {
+        let to = SocketAddr::try_from(to).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if !self.net.is_registered(self.addr, self.id) {
+            return Err(Status::unavailable("local member is down"));
+        }
+        if self.net.lookup(to).is_none() {
+            return Err(Status::unavailable("connection refused"));
+        }
+
+        Ok(Box::new(MemRemote {
+            net: self.net.clone(),
+            from: self.addr,
+            from_id: self.id,
+            to,
+        }))
+    }
+}
+
+/// A member reached over a [MemNetwork].
+struct MemRemote {
+    net: MemNetwork,
+    from: SocketAddr,
+    from_id: u64,
+    to: SocketAddr,
+}
+
+impl MemRemote {
+    /// Deliver `req` to the remote member, and its response back to us.
+    async fn deliver<T, R, F, Fut>(&self, req: Request<T>, f: F) -> GrpcResponse<R>
+    where
+        F: FnOnce(Arc<Cluster>, Request<T>) -> Fut,
+        Fut: Future<Output = GrpcResponse<R>>,
+    {
+        let sender_down = || Status::unavailable("local member is down");
+
+        self.net.traverse(self.from, self.to).await?;
+        if !self.net.is_registered(self.from, self.from_id) {
+            return Err(sender_down());
+        }
+        let remote = (self.net.lookup(self.to))
+            .ok_or_else(|| Status::unavailable("connection reset"))?;
+
+        let resp = f(remote, req).await;
+        self.net.traverse(self.to, self.from).await?;
+        if !self.net.is_registered(self.from, self.from_id) {
+            return Err(sender_down());
+        }
+        resp
+    }
+}
+
+/// Implement [Membership] for each kind of remote member by forwarding every rpc to it, and
+/// for the served local member by verifying every rpc before handling it.
+macro_rules! forward_rpcs {
+    ($($rpc:ident($req:ty) -> $resp:ty;)*) => {
+        #[crate::async_trait]
+        impl Membership for Remote {
+            $(async fn $rpc(&self, req: Request<$req>) -> GrpcResponse<$resp> {
+                let resp = self.client.clone().$rpc(req).await;
+                self.evict_on_error(&resp);
+                resp
+            })*
+        }
+
+        #[crate::async_trait]
+        impl Membership for MemRemote {
+            $(async fn $rpc(&self, req: Request<$req>) -> GrpcResponse<$resp> {
+                self.deliver(req, |c, req| async move { Verified(c).$rpc(req).await }).await
+            })*
+        }
+
+        #[crate::async_trait]
+        impl Membership for Signed {
+            $(async fn $rpc(&self, mut req: Request<$req>) -> GrpcResponse<$resp> {
+                self.secret.sign(&mut req);
+                self.inner.$rpc(req).await
+            })*
+        }
+
+        #[crate::async_trait]
+        impl Membership for Verified {
+            $(async fn $rpc(&self, req: Request<$req>) -> GrpcResponse<$resp> {
+                if let Some(secret) = self.0.cfg.secret.as_ref() {
+                    secret.verify(&req)?;
+                }
+                self.0.admitted().await?;
+                self.0.$rpc(req).await
+            })*
+        }
+    };
+}
+
+forward_rpcs! {
+    pre_join(PreJoinReq) -> PreJoinResp;
+    join(JoinReq) -> JoinResp;
+    batched_alert(BatchedAlertReq) -> Ack;
+    fast_accepted(FastAcceptedReq) -> Ack;
+    prepare(PrepareReq) -> Ack;
+    promise(PromiseReq) -> Ack;
+    accept(AcceptReq) -> Ack;
+    accepted(AcceptedReq) -> Ack;
+    broadcast(BroadcastReq) -> Ack;
+    probe(Ack) -> ProbeResp;
+    probe_via(ProbeViaReq) -> Ack;
+    leave(LeaveReq) -> Ack;
+    update_metadata(UpdateMetadataReq) -> Ack;
+    discover(DiscoverReq) -> DiscoverResp;
+    fetch_config(FetchConfigReq) -> JoinResp;
+}
@@ -4,19 +4,9 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-use rand::random;
-use std::{
-    cmp::Ordering,
-    collections::BTreeSet,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
-
-fn unixtime() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("not to be approaching a spacetime singularity")
-        .as_secs()
-}
+use crate::{clock::unixtime, rng::local_rng};
+use rand::Rng;
+use std::{cmp::Ordering, collections::BTreeSet, time::Duration};
 
 /// An asynchronous event id.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -47,7 +37,7 @@ impl Id {
 
     /// Generates a new event id.
     pub fn generate() -> Self {
-        Self::new(unixtime(), random())
+        Self::new(unixtime(), local_rng().gen())
     }
 
     /// Returns an event id that is ~always expired.
@@ -6,14 +6,18 @@
 // copied, modified, or distributed except according to those terms.
 use fnv::FnvBuildHasher;
 use std::{
-    collections::{btree_set::IntoIter, BTreeSet},
+    collections::{btree_set::IntoIter, BTreeMap, BTreeSet, HashMap},
     hash::{BuildHasher, Hash, Hasher},
     iter::{FromIterator, Map},
     mem::{self, ManuallyDrop},
     ops::{Bound, RangeBounds},
     ptr,
+    sync::{Arc, Mutex},
 };
 
+/// The ring-tagged successors of every entry in a [Tumbler].
+type Placement<T> = HashMap<T, Vec<(usize, T)>>;
+
 /// A bound around `T` that doesn't drop any `T`s.
 struct UnsafeBounds<T> {
     s: Bound<ManuallyDrop<(u64, T)>>,
@@ -51,10 +55,20 @@ impl<T> RangeBounds<(u64, T)> for UnsafeBounds<T> {
 /// [ 3, 2, 5, 0, 1, 4 ] ring: 3
 /// ```
 ///
+/// Entries may be placed in a zone (e.g. a rack or availability zone) with
+/// [insert_in_zone][Self::insert_in_zone]. Once entries span at least two zones, the edges of
+/// each ring are chosen so that the predecessors of an entry are spread across as many zones
+/// (other than its own) as possible. Placement only depends on the contents of the tumbler,
+/// so it's identical across tumblers with the same entries.
+///
 /// [expander]: https://en.wikipedia.org/wiki/Expander_graph
 pub struct Tumbler<T, S = FnvBuildHasher> {
     hasher: S,
     rings: Vec<BTreeSet<(u64, T)>>,
+    zones: HashMap<T, u64>,
+    zone_sizes: BTreeMap<u64, usize>,
+    /// The ring-tagged successors of every entry, computed lazily if zones are in use.
+    placement: Mutex<Option<Arc<Placement<T>>>>,
 }
 
 impl<T: Ord + Hash + Clone, S: BuildHasher> Extend<T> for Tumbler<T, S> {
@@ -106,7 +120,13 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
 
         let mut rings = Vec::with_capacity(size);
         rings.resize_with(size, Default::default);
-        Self { hasher, rings }
+        Self {
+            hasher,
+            rings,
+            zones: HashMap::new(),
+            zone_sizes: BTreeMap::new(),
+            placement: Mutex::new(None),
+        }
     }
 
     fn hash(&self, seed: usize, val: &T) -> u64 {
@@ -136,6 +156,11 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
         self.ring(0)
     }
 
+    /// Returns an iterator over each ring in the tumbler, in order.
+    pub fn rings(&self) -> impl Iterator<Item = impl DoubleEndedIterator<Item = &T>> {
+        (0..self.size()).map(move |idx| self.ring(idx))
+    }
+
     fn ring(&self, idx: usize) -> impl DoubleEndedIterator<Item = &T> {
         self.rings[idx].iter().map(|(_, v)| v)
     }
@@ -166,8 +191,29 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
         self.rings[idx].range(bounds).map(|(_, v)| v)
     }
 
+    /// Returns whether edges are being spread across zones, which is the case if entries
+    /// span at least two of them.
+    fn is_zoned(&self) -> bool {
+        self.zone_sizes.len() >= 2
+    }
+
+    /// Insert `val` into the tumbler, placing it in `zone`. Returns whether it was inserted.
+    ///
+    /// If `val` was already present, its zone is left unchanged.
+    pub fn insert_in_zone(&mut self, val: T, zone: u64) -> bool {
+        if !self.insert(val.clone()) {
+            return false;
+        }
+
+        self.zones.insert(val, zone);
+        *self.zone_sizes.entry(zone).or_default() += 1;
+        true
+    }
+
     /// Insert `val` into the tumbler. Returns whether it was inserted.
     pub fn insert(&mut self, val: T) -> bool {
+        self.placement.get_mut().unwrap().take();
+
         // if there's only one ring, we can take ownership right away.
         if self.rings.len() == 1 {
             let h = self.hash(0, &val);
@@ -196,6 +242,16 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
 
     /// Remove `val` from the tumbler. Returns whether it was removed.
     pub fn remove(&mut self, val: &T) -> bool {
+        self.placement.get_mut().unwrap().take();
+
+        if let Some(zone) = self.zones.remove(val) {
+            let n = self.zone_sizes.get_mut(&zone).unwrap();
+            *n -= 1;
+            if *n == 0 {
+                self.zone_sizes.remove(&zone);
+            }
+        }
+
         // NOTE(invariant): if present in any ring, val is equivalent in all rings.
         let mut entry = false;
         for ring in 0..self.rings.len() {
@@ -236,12 +292,86 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
     ///
     /// [4, 5, 0, 4]
     /// ```
+    ///
+    /// If entries span several zones, each edge is instead the nearest leading entry in a
+    /// zone other than that of `e` and those picked on previous rings, which may skip over
+    /// some entries. An entry is never picked on more than one ring while there are others
+    /// left to pick, falling back to the nearest unpicked entry in any zone. `e` need not be
+    /// contained in the tumbler, in which case its zone is considered to be unknown.
     pub fn predecessors<'a>(&'a self, e: &'a T) -> impl DoubleEndedIterator<Item = &T> {
-        (0..self.size()).flat_map(move |k| {
-            self.range(k, ..e)
-                .next_back()
-                .or_else(|| self.ring(k).next_back())
-        })
+        let edges: Vec<_> = if self.is_zoned() {
+            self.spread_predecessors(e)
+        } else {
+            (0..self.size())
+                .flat_map(|k| {
+                    self.range(k, ..e)
+                        .next_back()
+                        .or_else(|| self.ring(k).next_back())
+                })
+                .collect()
+        };
+
+        edges.into_iter()
+    }
+
+    /// Returns the leading edges of `e`, spread across zones.
+    fn spread_predecessors(&self, e: &T) -> Vec<&T> {
+        let zone = self.zones.get(e);
+        let other_zones = (self.zone_sizes.keys())
+            .filter(|z| Some(*z) != zone)
+            .count();
+
+        let mut used = BTreeSet::new();
+        let mut edges = Vec::with_capacity(self.size());
+
+        for k in 0..self.size() {
+            // walk backwards around the ring (once), starting just before e.
+            let walk = (self.range(k, ..e).rev())
+                .chain(self.ring(k).rev())
+                .filter(|c| *c != e)
+                .take(self.len());
+
+            // prefer an unused zone, then any other zone, then the nearest unpicked entry.
+            let (mut unused, mut other, mut first, mut nearest) = (None, None, None, None);
+            for c in walk {
+                nearest = nearest.or(Some(c));
+
+                // a single entry mustn't observe e on several rings, or it alone could
+                // report e as faulty (and leave e unobserved if it fails itself).
+                if edges.contains(&c) {
+                    continue;
+                }
+                first = first.or(Some(c));
+
+                let z = self.zones.get(c);
+                if z == zone {
+                    continue;
+                }
+                other = other.or(Some(c));
+
+                if !matches!(z, Some(z) if used.contains(z)) {
+                    unused = Some(c);
+                    break;
+                }
+            }
+
+            let edge = match unused.or(other).or(first).or(nearest) {
+                Some(edge) => edge,
+                None => continue,
+            };
+
+            if let Some(z) = self.zones.get(edge) {
+                used.insert(*z);
+            }
+            // once every other zone has been used, start spreading over them again.
+            if used.len() >= other_zones {
+                used.clear();
+            }
+
+            edges.push(edge);
+        }
+
+        edges
     }
 
     /// Returns an iterator over every trailing edge of `e`, across all rings.
@@ -262,12 +392,62 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
     ///
     /// [1, 4, 4, 2]
     /// ```
+    ///
+    /// If entries span several zones, these are the entries that `e` is a predecessor of,
+    /// which may be more or less than one per ring. See [successor_edges][Self::successor_edges]
+    /// for which rings they're on.
     pub fn successors<'a>(&'a self, e: &'a T) -> impl DoubleEndedIterator<Item = &T> {
-        (0..self.size()).flat_map(move |k| {
-            self.range(k, (Bound::Excluded(e), Bound::Unbounded))
-                .next()
-                .or_else(|| self.ring(k).next())
-        })
+        self.successor_edges(e).map(|(_, v)| v)
+    }
+
+    /// Returns an iterator over every trailing edge of `e`, along with the ring it's on.
+    pub fn successor_edges<'a>(
+        &'a self,
+        e: &'a T,
+    ) -> impl DoubleEndedIterator<Item = (usize, &'a T)> {
+        let edges: Vec<_> = if self.is_zoned() {
+            // the placement holds copies of each entry, so look up the ones we own.
+            (self.placement().get(e).into_iter())
+                .flat_map(|edges| edges.iter())
+                .flat_map(|(k, v)| self.get(v).map(|v| (*k, v)))
+                .collect()
+        } else {
+            (0..self.size())
+                .flat_map(|k| {
+                    self.range(k, (Bound::Excluded(e), Bound::Unbounded))
+                        .next()
+                        .or_else(|| self.ring(k).next())
+                        .map(|v| (k, v))
+                })
+                .collect()
+        };
+
+        edges.into_iter()
+    }
+
+    /// Returns the ring-tagged successors of every entry, which are the inverse of their
+    /// (zone-spread) predecessors.
+    fn placement(&self) -> Arc<Placement<T>> {
+        let mut cached = self.placement.lock().unwrap();
+        if let Some(placement) = cached.as_ref() {
+            return Arc::clone(placement);
+        }
+
+        let mut placement: HashMap<_, Vec<_>> = HashMap::with_capacity(self.len());
+        for subject in self.iter() {
+            for (k, observer) in self.spread_predecessors(subject).into_iter().enumerate() {
+                (placement.entry(observer.clone()).or_default()).push((k, subject.clone()));
+            }
+        }
+
+        placement.values_mut().for_each(|edges| edges.sort());
+        Arc::clone(cached.get_or_insert(Arc::new(placement)))
+    }
+
+    /// Returns the entry in the tumbler that is equal to `val`, if any.
+    fn get(&self, val: &T) -> Option<&T> {
+        self.range(0, (Bound::Included(val), Bound::Included(val)))
+            .next()
     }
 
     /// Clear the tumbler, removing all entries.
@@ -275,6 +455,9 @@ impl<T: Ord + Hash + Clone, S: BuildHasher> Tumbler<T, S> {
         for ring in self.rings.iter_mut() {
             ring.clear();
         }
+        self.zones.clear();
+        self.zone_sizes.clear();
+        self.placement.get_mut().unwrap().take();
     }
 }
 
@@ -335,6 +518,75 @@ mod tests {
         assert_ne!(rings[2], rings[0]);
     }
 
+    fn zoned(k: usize, input: impl Iterator<Item = u32>) -> Tumbler<u32> {
+        let mut t = Tumbler::new(k);
+        for v in input {
+            t.insert_in_zone(v, (v % 3).into());
+        }
+        t
+    }
+
+    #[test]
+    fn predecessors_are_spread_across_zones() {
+        let t = zoned(4, 0..30);
+
+        for e in t.iter() {
+            let zones: Vec<_> = t.predecessors(e).map(|p| t.zones[p]).collect();
+            let distinct: HashSet<_> = zones.iter().collect();
+
+            assert_eq!(4, zones.len());
+            assert!(!zones.contains(&t.zones[e]));
+            assert_eq!(2, distinct.len());
+        }
+    }
+
+    #[test]
+    fn predecessors_are_distinct_across_uneven_zones() {
+        // a lone entry in zone 1 can only observe each entry in zone 0 on one ring.
+        let mut t = Tumbler::new(4);
+        for v in 0..9 {
+            t.insert_in_zone(v, 0);
+        }
+        t.insert_in_zone(100, 1);
+
+        for e in t.iter() {
+            let observers: Vec<_> = t.predecessors(e).collect();
+            let distinct: HashSet<_> = observers.iter().collect();
+
+            assert_eq!(4, observers.len());
+            assert_eq!(4, distinct.len());
+        }
+    }
+
+    #[test]
+    fn zoned_successors_are_inverse_of_predecessors() {
+        let mut t = zoned(4, 0..30);
+        t.remove(&7);
+        t.insert(100);
+
+        let mut edges = 0;
+        for e in t.iter() {
+            for (k, s) in t.successor_edges(e) {
+                assert_eq!(Some(e), t.predecessors(s).nth(k));
+                edges += 1;
+            }
+        }
+        assert_eq!(t.len() * t.size(), edges);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn zoned_placement_is_deterministic(input: HashSet<u32>) -> bool {
+        let a = zoned(3, input.iter().copied());
+        let mut input: Vec<_> = input.into_iter().collect();
+        input.sort();
+        let b = zoned(3, input.into_iter().rev());
+
+        let same = a.iter().all(|e| {
+            a.predecessors(e).eq(b.predecessors(e)) && a.successor_edges(e).eq(b.successor_edges(e))
+        });
+        same
+    }
+
     #[test]
     fn drop_values_dont_explode() {
         let mut t = Tumbler::new(2);
@@ -18,8 +18,9 @@
 //!
 //! # Service Discovery
 //! `blip` is designed to build heterogenous meshes. As such, members may expose arbitrary
-//! (immutable) key-value metadata when they join a mesh, which can be used for the purpose
-//! of service discovery.
+//! key-value metadata when they join a mesh, which can be used for the purpose of service
+//! discovery. Metadata can be updated later on, and updates are agreed upon by the mesh in
+//! the same way as joins and departures.
 //!
 //! # Sharding and State
 //! `blip` does not enforce any invariants with regard to state held by members of a mesh.
@@ -29,6 +30,9 @@
 //! # Feature Flags
 //! * `full`: Enables all optional features.
 //! * `cache`: Enables the [cache][service::cache] service.
+//! * `sim`: Enables the deterministic [simulator][sim].
+//! * `cli`: Builds `blipctl`, a tool for operating members that serve the
+//!   [admin][cluster::admin] service.
 //!
 //! # References
 //! * [Stable and Consistent Membership at Scale with Rapid][rapid]
@@ -61,16 +65,21 @@ fn test_html_root_url() {
 #[macro_use]
 mod macros;
 
+mod clock;
 mod collections;
+mod rng;
 
 pub mod cluster;
 pub mod overlay;
 pub mod service;
+#[cfg(feature = "sim")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sim")))]
+pub mod sim;
 
 #[doc(inline)]
 pub use cluster::cut::{Member, MultiNodeCut, Subscription};
 #[doc(inline)]
-pub use overlay::{ExposedService, Mesh, MeshService};
+pub use overlay::{ExposedService, Mesh, MeshHandle, MeshService};
 
 /// A re-export of [async_trait] for convenience.
 ///
@@ -6,17 +6,28 @@
 // copied, modified, or distributed except according to those terms.
 //! Batteries-included grpc service mesh.
 use super::cluster::{
-    cut::{Closed, Subscription},
-    Cluster, Config,
+    admission::Admission,
+    cut::{Closed, MultiNodeCut, Subscription},
+    faultdetect::{FaultDetector, Suspicion},
+    metrics::Metrics,
+    transport::{MemNetwork, Tonic},
+    Cluster, Config, JoinError, Secret,
 };
 
 use futures::{
     future::{pending, FutureExt, TryFutureExt},
-    stream::{FuturesUnordered, StreamExt},
+    stream::{self, FuturesUnordered, Stream, StreamExt},
+};
+use std::{
+    collections::HashMap, error, future::Future, io, net::SocketAddr, path::PathBuf, result,
+    sync::Arc, time::Duration,
 };
-use std::{error, future::Future, net::SocketAddr, result, sync::Arc, time::Duration};
 use thiserror::Error;
-use tokio::select;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{watch, Notify},
+};
 use tonic::{
     body::BoxBody,
     codegen::{
@@ -28,6 +39,7 @@ use tonic::{
         server::{Router, Unimplemented},
         Body, ClientTlsConfig, NamedService, Server, ServerTlsConfig,
     },
+    Status,
 };
 use tracing::Span;
 
@@ -44,9 +56,25 @@ pub enum Error {
     /// An error encountered when an internal task exits unexpectedly.
     #[error("mesh: task closed")]
     Closed(#[from] Closed),
+
+    /// An error encountered when the local node is refused entry to the mesh.
+    #[error("mesh: {}", .0)]
+    Join(#[from] JoinError),
+
+    /// An error encountered when inspecting a listener passed to the mesh.
+    #[error("mesh: {}", .0)]
+    Io(#[from] io::Error),
+
+    /// An error encountered when the local node would be identified by port 0, which other
+    /// members can't connect to.
+    #[error("mesh: can't identify as {} (use serve_with_incoming for ephemeral ports)", .0)]
+    EphemeralPort(SocketAddr),
 }
 
 /// Specifies observer/subject thresholds for the cut detector.
+///
+/// Presets are provided for [small][Self::small] and [large][Self::large] meshes, and for
+/// meshes with [high churn][Self::high_churn].
 #[derive(Copy, Clone, Debug)]
 pub struct CutDetectorConfig {
     /// Threshold of reports required to place a subject into unstable report mode.
@@ -87,7 +115,326 @@ impl CutDetectorConfig {
         }
     }
 
-    // TODO: add some sensible default configs for different scenarios
+    /// Returns a configuration for small meshes of fewer than 10 members, where each
+    /// observer would otherwise be assigned the same subject on several rings.
+    pub const fn small() -> Self {
+        Self {
+            unstable_threshold: 1,
+            stable_threshold: 3,
+            subjects_per_observer: 4,
+        }
+    }
+
+    /// Returns a configuration for large meshes of several hundred members or more, which
+    /// uses more observers per subject to tolerate a greater number of faulty observers.
+    pub const fn large() -> Self {
+        Self {
+            unstable_threshold: 6,
+            stable_threshold: 16,
+            subjects_per_observer: 20,
+        }
+    }
+
+    /// Returns a configuration for meshes where members join and leave frequently. The wide
+    /// gap between thresholds holds back view-changes while reports about concurrent changes
+    /// are still arriving, so that they tend to be batched into fewer view-changes.
+    pub const fn high_churn() -> Self {
+        Self {
+            unstable_threshold: 2,
+            stable_threshold: 8,
+            subjects_per_observer: 10,
+        }
+    }
+
+    /// Check that the invariants listed on each field are upheld.
+    pub fn validate(&self) -> result::Result<(), CutDetectorConfigError> {
+        use CutDetectorConfigError::*;
+
+        if self.unstable_threshold == 0 {
+            return Err(ZeroUnstableThreshold);
+        }
+        if self.unstable_threshold > self.stable_threshold {
+            return Err(UnstableAboveStable {
+                unstable: self.unstable_threshold,
+                stable: self.stable_threshold,
+            });
+        }
+        if self.stable_threshold > self.subjects_per_observer {
+            return Err(StableAboveSubjects {
+                stable: self.stable_threshold,
+                subjects: self.subjects_per_observer,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// An error describing which invariant of a [CutDetectorConfig] was violated.
+#[derive(Copy, Clone, Debug, Error, PartialEq, Eq)]
+pub enum CutDetectorConfigError {
+    /// The unstable threshold was zero.
+    #[error("unstable_threshold must be non-zero")]
+    ZeroUnstableThreshold,
+
+    /// The unstable threshold was greater than the stable threshold.
+    #[error("unstable_threshold ({}) must not exceed stable_threshold ({})", .unstable, .stable)]
+    UnstableAboveStable {
+        /// The unstable threshold.
+        unstable: usize,
+        /// The stable threshold.
+        stable: usize,
+    },
+
+    /// The stable threshold was greater than the number of subjects per observer.
+    #[error(
+        "stable_threshold ({}) must not exceed subjects_per_observer ({})",
+        .stable,
+        .subjects
+    )]
+    StableAboveSubjects {
+        /// The stable threshold.
+        stable: usize,
+        /// The number of subjects per observer.
+        subjects: usize,
+    },
+}
+
+/// Specifies the timings used by the membership protocol.
+///
+/// Presets are provided for meshes on a [lan][Self::lan] (the default), on a
+/// [wan][Self::wan], and for [tests][Self::test].
+#[derive(Copy, Clone, Debug)]
+pub struct ProtocolTimings {
+    /// How long alerts are collected before being broadcast in a batch.
+    ///
+    /// Must be non-zero, and less than `px_delay_min`.
+    pub alert_batch: Duration,
+
+    /// The minimum delay before falling back to a classical paxos round when a fast round
+    /// fails to reach a decision.
+    ///
+    /// Must be non-zero, and less than `px_delay_scale`.
+    pub px_delay_min: Duration,
+
+    /// Scales the maximum delay before falling back to a classical paxos round, which is
+    /// `px_delay_scale * log2(members + 1)`. Each member picks a random delay between the
+    /// minimum and maximum, so that few of them contend to coordinate the round.
+    pub px_delay_scale: Duration,
+
+    /// The initial delay between failed join attempts, which doubles after each failure.
+    ///
+    /// Must be non-zero, and less than or equal to `join_retry_max`.
+    pub join_retry_min: Duration,
+
+    /// The maximum delay between failed join attempts.
+    pub join_retry_max: Duration,
+
+    /// The initial amount of time to wait for a join attempt to complete, which grows by
+    /// half after each failure.
+    ///
+    /// Must be non-zero, and less than or equal to `join_timeout_max`.
+    pub join_timeout_min: Duration,
+
+    /// The maximum amount of time to wait for a join attempt to complete.
+    pub join_timeout_max: Duration,
+
+    /// How long a member that is leaving waits for the view-change that removes it from the
+    /// configuration, after its observers have accepted its departure.
+    ///
+    /// Must be non-zero.
+    pub leave_timeout: Duration,
+
+    /// How long broadcast ids are remembered in order to drop redundant deliveries.
+    ///
+    /// Must be at least one second, and greater than `px_delay_scale`.
+    pub broadcast_window: Duration,
+}
+
+impl Default for ProtocolTimings {
+    fn default() -> Self {
+        Self::lan()
+    }
+}
+
+impl ProtocolTimings {
+    /// Returns timings suitable for members on the same local network.
+    pub const fn lan() -> Self {
+        Self {
+            alert_batch: Duration::from_millis(100),
+            px_delay_min: Duration::from_secs(1),
+            px_delay_scale: Duration::from_secs(4),
+            join_retry_min: Duration::from_millis(200),
+            join_retry_max: Duration::from_secs(4),
+            join_timeout_min: Duration::from_secs(5),
+            join_timeout_max: Duration::from_secs(15),
+            leave_timeout: Duration::from_secs(15),
+            broadcast_window: Duration::from_secs(3600),
+        }
+    }
+
+    /// Returns timings suitable for members spread across a wide area network, where round
+    /// trips are slower and less predictable.
+    pub const fn wan() -> Self {
+        Self {
+            alert_batch: Duration::from_millis(250),
+            px_delay_min: Duration::from_secs(3),
+            px_delay_scale: Duration::from_secs(10),
+            join_retry_min: Duration::from_millis(500),
+            join_retry_max: Duration::from_secs(10),
+            join_timeout_min: Duration::from_secs(10),
+            join_timeout_max: Duration::from_secs(30),
+            leave_timeout: Duration::from_secs(30),
+            broadcast_window: Duration::from_secs(3600),
+        }
+    }
+
+    /// Returns timings suitable for tests, where all members run on the same host.
+    pub const fn test() -> Self {
+        Self {
+            alert_batch: Duration::from_millis(20),
+            px_delay_min: Duration::from_millis(200),
+            px_delay_scale: Duration::from_secs(1),
+            join_retry_min: Duration::from_millis(50),
+            join_retry_max: Duration::from_secs(1),
+            join_timeout_min: Duration::from_secs(2),
+            join_timeout_max: Duration::from_secs(5),
+            leave_timeout: Duration::from_secs(5),
+            broadcast_window: Duration::from_secs(600),
+        }
+    }
+
+    /// Check that the invariants listed on each field are upheld.
+    pub fn validate(&self) -> result::Result<(), ProtocolTimingsError> {
+        use ProtocolTimingsError::*;
+
+        let zero = Duration::from_secs(0);
+
+        if self.alert_batch == zero {
+            return Err(ZeroAlertBatch);
+        }
+        if self.alert_batch >= self.px_delay_min {
+            return Err(AlertBatchNotBelowPxDelay {
+                alert_batch: self.alert_batch,
+                px_delay_min: self.px_delay_min,
+            });
+        }
+        if self.px_delay_min >= self.px_delay_scale {
+            return Err(PxDelayMinNotBelowScale {
+                min: self.px_delay_min,
+                scale: self.px_delay_scale,
+            });
+        }
+        if self.join_retry_min == zero {
+            return Err(ZeroJoinRetry);
+        }
+        if self.join_retry_min > self.join_retry_max {
+            return Err(JoinRetryMinAboveMax {
+                min: self.join_retry_min,
+                max: self.join_retry_max,
+            });
+        }
+        if self.join_timeout_min == zero {
+            return Err(ZeroJoinTimeout);
+        }
+        if self.join_timeout_min > self.join_timeout_max {
+            return Err(JoinTimeoutMinAboveMax {
+                min: self.join_timeout_min,
+                max: self.join_timeout_max,
+            });
+        }
+        if self.leave_timeout == zero {
+            return Err(ZeroLeaveTimeout);
+        }
+        if self.broadcast_window < Duration::from_secs(1) {
+            return Err(BroadcastWindowTooShort(self.broadcast_window));
+        }
+        if self.broadcast_window <= self.px_delay_scale {
+            return Err(BroadcastWindowNotAboveScale {
+                window: self.broadcast_window,
+                scale: self.px_delay_scale,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// An error describing which invariant of a [ProtocolTimings] was violated.
+#[derive(Copy, Clone, Debug, Error, PartialEq, Eq)]
+pub enum ProtocolTimingsError {
+    /// The alert batch was zero.
+    #[error("alert_batch must be non-zero")]
+    ZeroAlertBatch,
+
+    /// The alert batch was not less than the minimum paxos delay.
+    #[error(
+        "alert_batch ({:?}) must be less than px_delay_min ({:?})",
+        .alert_batch,
+        .px_delay_min
+    )]
+    AlertBatchNotBelowPxDelay {
+        /// The alert batch.
+        alert_batch: Duration,
+        /// The minimum paxos delay.
+        px_delay_min: Duration,
+    },
+
+    /// The minimum paxos delay was not less than the paxos delay scale.
+    #[error("px_delay_min ({:?}) must be less than px_delay_scale ({:?})", .min, .scale)]
+    PxDelayMinNotBelowScale {
+        /// The minimum paxos delay.
+        min: Duration,
+        /// The paxos delay scale.
+        scale: Duration,
+    },
+
+    /// The initial join retry delay was zero.
+    #[error("join_retry_min must be non-zero")]
+    ZeroJoinRetry,
+
+    /// The initial join retry delay was greater than the maximum.
+    #[error("join_retry_min ({:?}) must not exceed join_retry_max ({:?})", .min, .max)]
+    JoinRetryMinAboveMax {
+        /// The initial join retry delay.
+        min: Duration,
+        /// The maximum join retry delay.
+        max: Duration,
+    },
+
+    /// The initial join timeout was zero.
+    #[error("join_timeout_min must be non-zero")]
+    ZeroJoinTimeout,
+
+    /// The initial join timeout was greater than the maximum.
+    #[error("join_timeout_min ({:?}) must not exceed join_timeout_max ({:?})", .min, .max)]
+    JoinTimeoutMinAboveMax {
+        /// The initial join timeout.
+        min: Duration,
+        /// The maximum join timeout.
+        max: Duration,
+    },
+
+    /// The leave timeout was zero.
+    #[error("leave_timeout must be non-zero")]
+    ZeroLeaveTimeout,
+
+    /// The broadcast window was shorter than one second.
+    #[error("broadcast_window ({:?}) must be at least one second", .0)]
+    BroadcastWindowTooShort(Duration),
+
+    /// The broadcast window was not greater than the paxos delay scale.
+    #[error(
+        "broadcast_window ({:?}) must be greater than px_delay_scale ({:?})",
+        .window,
+        .scale
+    )]
+    BroadcastWindowNotAboveScale {
+        /// The broadcast window.
+        window: Duration,
+        /// The paxos delay scale.
+        scale: Duration,
+    },
 }
 
 /// An unstarted member of a blip mesh network.
@@ -111,12 +458,29 @@ impl Default for Mesh<Server> {
             cfg: Config {
                 lh: (cd.unstable_threshold, cd.stable_threshold),
                 k: cd.subjects_per_observer,
-                seed: None,
+                seeds: Vec::new(),
+                shuffle_seeds: false,
+                bootstrap_expect: None,
+                bootstrap_peers: Vec::new(),
+                merge_interval: None,
+                merge_peers: Vec::new(),
                 meta: Default::default(),
                 server_tls: false,
                 client_tls: None,
                 fd_timeout: Duration::from_secs(2),
                 fd_strikes: 3,
+                fd: None,
+                indirect_probes: 3,
+                admission: None,
+                cluster_name: String::new(),
+                persist: None,
+                static_recovery: false,
+                metrics: Metrics::new(),
+                admin: false,
+                timings: ProtocolTimings::lan(),
+                zone_key: None,
+                secret: None,
+                advertise: None,
             },
             grpc: Server::builder(),
             svcs: Vec::new(),
@@ -145,37 +509,224 @@ impl Mesh<Server> {
 
 /// Methods for blip-specific membership protocol configuration.
 impl<R> Mesh<R> {
-    /// Configure the cut detector. All members of the mesh must have the same configuration.
+    /// Configure the cut detector. All members of the mesh must have the same configuration,
+    /// and nodes with a different configuration will be refused entry.
     ///
     /// # Panics
-    /// Panics if any invariants (listed at [CutDetectorConfig]) are not upheld.
-    pub fn cd_config(mut self, cfg: CutDetectorConfig) -> Self {
-        assert_ne!(0, cfg.unstable_threshold);
-        assert_ne!(0, cfg.stable_threshold);
-        assert_ne!(0, cfg.subjects_per_observer);
-        assert!(cfg.unstable_threshold <= cfg.stable_threshold);
-        assert!(cfg.stable_threshold <= cfg.subjects_per_observer);
+    /// Panics if any invariants (listed at [CutDetectorConfig]) are not upheld. See
+    /// [try_cd_config][Self::try_cd_config] for a fallible alternative.
+    pub fn cd_config(self, cfg: CutDetectorConfig) -> Self {
+        match self.try_cd_config(cfg) {
+            Ok(mesh) => mesh,
+            Err(e) => panic!("invalid cut detector config: {}", e),
+        }
+    }
+
+    /// Configure the cut detector, returning an error if any invariants (listed at
+    /// [CutDetectorConfig]) are not upheld.
+    pub fn try_cd_config(
+        mut self,
+        cfg: CutDetectorConfig,
+    ) -> result::Result<Self, CutDetectorConfigError> {
+        cfg.validate()?;
 
         self.cfg.lh.0 = cfg.unstable_threshold;
         self.cfg.lh.1 = cfg.stable_threshold;
         self.cfg.k = cfg.subjects_per_observer;
+        Ok(self)
+    }
+
+    /// Configure the timings used by the membership protocol. All members of the mesh should
+    /// have the same configuration.
+    ///
+    /// # Panics
+    /// Panics if any invariants (listed at [ProtocolTimings]) are not upheld. See
+    /// [try_timings][Self::try_timings] for a fallible alternative.
+    pub fn timings(self, t: ProtocolTimings) -> Self {
+        match self.try_timings(t) {
+            Ok(mesh) => mesh,
+            Err(e) => panic!("invalid protocol timings: {}", e),
+        }
+    }
+
+    /// Configure the timings used by the membership protocol, returning an error if any
+    /// invariants (listed at [ProtocolTimings]) are not upheld.
+    pub fn try_timings(
+        mut self,
+        t: ProtocolTimings,
+    ) -> result::Result<Self, ProtocolTimingsError> {
+        t.validate()?;
+
+        self.cfg.timings = t;
+        Ok(self)
+    }
+
+    /// Set the name of the cluster. Members will refuse joins and broadcasts from nodes that
+    /// were configured with a different name, which guards against separate meshes being
+    /// merged by a misconfigured seed.
+    ///
+    /// Defaults to an empty name.
+    pub fn cluster_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.cfg.cluster_name = name.into();
         self
     }
 
-    /// Set a seed node to contact in order to join an existing network. If this is left
-    /// unset (the default), a new mesh will be bootstrapped with the local node as the
-    /// sole member.
+    /// Authenticate every message exchanged between members with `secret`, which must be
+    /// shared by all members of the mesh. Messages that weren't signed with it are rejected,
+    /// so hosts outside of the mesh can't impersonate members (e.g. to vote them out of the
+    /// configuration). Each message is also signed with a unique id and the time it was sent,
+    /// and members reject any message they've already seen or that is more than five minutes
+    /// old, so members' clocks must be roughly synchronized.
+    ///
+    /// # Limitations
+    /// The secret doesn't distinguish members from one another, and the sender a message
+    /// claims to be from isn't checked against the connection it arrived on. Any holder of
+    /// the secret can therefore sign messages on behalf of any member, and so still raise
+    /// alerts as other observers to vote members out of the configuration. Only share the
+    /// secret between hosts that are trusted not to do so.
+    ///
+    /// Messages are authenticated, but not encrypted. Members should be served with tls if
+    /// the network between them isn't trusted.
+    pub fn cluster_secret<S: AsRef<[u8]>>(mut self, secret: S) -> Self {
+        self.cfg.secret = Some(Secret::new(secret.as_ref()));
+        self
+    }
+
+    /// Set the address that other members should use to reach the local node, if it differs
+    /// from the one the mesh is served on. This is necessary when serving on an unspecified
+    /// address (like `0.0.0.0`), or from behind a NAT.
+    ///
+    /// The advertised address identifies the local node in the mesh, and is reported by
+    /// [MultiNodeCut::local_addr].
+    ///
+    /// Defaults to the address the mesh is served on.
+    pub fn advertise_addr(mut self, addr: SocketAddr) -> Self {
+        self.cfg.advertise = Some(addr);
+        self
+    }
+
+    /// Add a seed node to contact in order to join an existing network. If no seeds are set
+    /// (the default), a new mesh will be bootstrapped with the local node as the sole member.
     pub fn join_seed(mut self, addr: SocketAddr, use_tls: bool) -> Self {
-        self.cfg.seed = Some((addr, use_tls).into());
+        self.cfg.seeds.push((addr, use_tls).into());
+        self
+    }
+
+    /// Add several seed nodes to contact in order to join an existing network, each with
+    /// its own tls setting.
+    ///
+    /// Join attempts rotate through all configured seeds (in the order they were added,
+    /// unless [shuffle_seeds][Self::shuffle_seeds] is set), backing off exponentially
+    /// as failures are encountered.
+    pub fn join_seeds<I: IntoIterator<Item = (SocketAddr, bool)>>(mut self, seeds: I) -> Self {
+        self.cfg.seeds.extend(seeds.into_iter().map(Into::into));
+        self
+    }
+
+    /// Wait until `expect` nodes (including the local one) out of a static list of `peers`
+    /// are reachable before forming a new mesh, rather than bootstrapping one immediately.
+    ///
+    /// Every node should be given the same peers and expected size. Once enough of them are
+    /// reachable, and every one of them has discovered the same set of nodes, the one with
+    /// the lowest address bootstraps the mesh and the rest join it. Nodes that can't all reach
+    /// each other keep waiting, so a fleet that starts at the same time forms exactly one
+    /// mesh. If any of the peers is already a member of a mesh, that mesh is joined instead.
+    ///
+    /// This only applies when there aren't any seed nodes to join.
+    ///
+    /// # Panics
+    /// Panics if `expect` is zero.
+    pub fn bootstrap_expect<I>(mut self, expect: usize, peers: I) -> Self
+    where I: IntoIterator<Item = (SocketAddr, bool)> {
+        assert!(expect > 0);
+
+        self.cfg.bootstrap_expect = Some(expect);
+        self.cfg.bootstrap_peers = peers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Periodically look for other meshes that formed apart from this one (such as after a
+    /// long partition), and merge them. Every `interval`, members ask each of `peers`, as
+    /// well as any seed nodes, bootstrap peers, and nodes they've previously seen in the
+    /// mesh, which mesh they're a member of.
+    ///
+    /// If a larger mesh answers, the members of the smaller one rejoin it. A cut in which the
+    /// local node rejoined is reported by [MultiNodeCut::is_merged].
+    ///
+    /// Disabled by default.
+    pub fn merge_partitions<I>(mut self, interval: Duration, peers: I) -> Self
+    where I: IntoIterator<Item = (SocketAddr, bool)> {
+        self.cfg.merge_interval = Some(interval);
+        self.cfg.merge_peers = peers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set whether to shuffle the order in which seed nodes are contacted. This spreads the
+    /// load of a cold start across all seeds, rather than having every node try the same
+    /// seed first.
+    ///
+    /// Defaults to false.
+    pub fn shuffle_seeds(mut self, shuffle: bool) -> Self {
+        self.cfg.shuffle_seeds = shuffle;
+        self
+    }
+
+    /// Persist a snapshot of the membership to `path` after every view-change, so that the
+    /// local node can find its way back to the mesh after a restart.
+    ///
+    /// On startup, members of the persisted configuration are contacted as seeds (alongside
+    /// any configured ones). Note that this means a node which would otherwise bootstrap a
+    /// new mesh will instead wait for some previously known member to come back.
+    pub fn persist<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.cfg.persist = Some(path.into());
+        self
+    }
+
+    /// Set whether to reinstate the persisted configuration directly on startup, rather
+    /// than joining through seeds. Has no effect unless [persist][Self::persist] is set.
+    ///
+    /// This allows a mesh to recover after every member restarts at once, as no seeds will
+    /// be available to join through. The recovered configuration includes every member of
+    /// the persisted one, so any that don't come back will be removed by the fault detector.
+    ///
+    /// This should only be enabled when all members restart together, as a member that
+    /// restarts alone will recover a configuration the rest of the mesh has moved on from.
+    ///
+    /// Defaults to false.
+    pub fn static_recovery(mut self, enabled: bool) -> Self {
+        self.cfg.static_recovery = enabled;
+        self
+    }
+
+    /// Spread the observers of each member across failure domains (e.g. racks or
+    /// availability zones), so that a domain going down can't hide the failure of members
+    /// outside of it. The domain of each member is read from its metadata at `key`, and is
+    /// fixed at the time it joins. All members of the mesh should have the same configuration.
+    ///
+    /// Observers are placed by pure hash order if unset (the default), or if members span
+    /// fewer than two domains.
+    pub fn zone_key<S: Into<String>>(mut self, key: S) -> Self {
+        self.cfg.zone_key = Some(key.into());
         self
     }
 
     /// Add metadata to distribute to other members of the mesh.
+    ///
+    /// Metadata can be changed after the mesh has started with [MeshHandle::update_metadata].
     pub fn add_metadata<I: IntoIterator<Item = (String, Vec<u8>)>>(mut self, iter: I) -> Self {
         self.cfg.meta.extend(iter);
         self
     }
 
+    /// Set an [Admission] hook to decide whether nodes may join the mesh through the local
+    /// node. This should be set to an equivalent hook on every member.
+    ///
+    /// Defaults to admitting all nodes.
+    pub fn admission<A: Admission>(mut self, admission: A) -> Self {
+        self.cfg.admission = Some(Box::new(admission));
+        self
+    }
+
     /// Configure TLS for outgoing connections to mesh members that are expecting TLS.
     ///
     /// This will also be exposed in the [MultiNodeCut][cut]s received from [Subscription]s.
@@ -203,7 +754,8 @@ impl<R> Mesh<R> {
     /// Set the number of successive fault detection probe attempts that must fail before a
     /// subject is marked as faulty.
     ///
-    /// Defaults to 3.
+    /// Defaults to 3. Has no effect if a custom detector is set with
+    /// [fault_detector][Self::fault_detector].
     ///
     /// # Panics
     /// Panics if `strikes == 0`.
@@ -213,6 +765,79 @@ impl<R> Mesh<R> {
         self
     }
 
+    /// Set the number of other members asked to probe a subject when a direct probe to it
+    /// fails. A probe is only considered failed if the subject also fails to respond to all
+    /// of the indirect probes, which prevents a single bad link from getting a healthy
+    /// subject removed from the mesh.
+    ///
+    /// Set to 0 to disable indirect probes. Defaults to 3.
+    pub fn indirect_probes(mut self, n: usize) -> Self {
+        self.cfg.indirect_probes = n;
+        self
+    }
+
+    /// Set the [FaultDetector] used to decide when observed subjects are faulty.
+    ///
+    /// Defaults to a [StrikeDetector][strikes] configured by
+    /// [fault_strikes][Self::fault_strikes].
+    ///
+    /// [strikes]: crate::cluster::faultdetect::StrikeDetector
+    pub fn fault_detector<D: FaultDetector>(mut self, detector: D) -> Self {
+        self.cfg.fd = Some(Box::new(detector));
+        self
+    }
+
+    /// Set whether to serve the [admin][crate::cluster::admin] service, which exposes the
+    /// internal protocol state of the local node, and lets clients ask it to leave the mesh.
+    ///
+    /// The admin service is served on the same address as the membership protocol, and is
+    /// protected the same way: if a [cluster_secret][Self::cluster_secret] is set, requests
+    /// must be signed with it (see [admin::sign][crate::cluster::admin::sign]), and if tls is
+    /// configured, clients must connect with it. Otherwise, anyone who can connect to the
+    /// local node can use it.
+    ///
+    /// Defaults to false.
+    pub fn admin(mut self, enabled: bool) -> Self {
+        self.cfg.admin = enabled;
+        self
+    }
+
+    /// Returns the [Metrics] registry the local node will record into once it is served.
+    pub fn metrics(&self) -> Metrics {
+        self.cfg.metrics.clone()
+    }
+
+    /// Consume this [Mesh], creating a future that will run the membership protocol on the
+    /// in-memory network `net` rather than over tcp, as well as a [MeshHandle] for the local
+    /// member. `addr` is only used to identify the member within `net`.
+    ///
+    /// Any [ExposedService]s that were added will not be served, but all [MeshService]s run
+    /// as usual. This is primarily useful to test mesh behavior under network faults without
+    /// opening any sockets.
+    ///
+    /// The future resolves once the mesh has exited.
+    pub fn serve_in_memory(
+        self,
+        net: &MemNetwork,
+        addr: SocketAddr,
+    ) -> (MeshHandle, impl Future<Output = Result>) {
+        let Mesh { cfg, svcs, .. } = self;
+        let net = net.clone();
+        let transport = Arc::new(net.transport(addr));
+        let cluster = Arc::new(Cluster::new(cfg, addr, Arc::clone(&transport) as _));
+        net.register(&transport, &cluster);
+        let handle = MeshHandle::new(Arc::clone(&cluster));
+
+        let signal = handle.shutdown_signal(pending());
+        let server = async move {
+            signal.await;
+            net.deregister(addr);
+            Ok(())
+        };
+
+        (handle, run(cluster, svcs, server))
+    }
+
     /// Add a [MeshService] that doesn't necessarily implement [ExposedService].
     ///
     /// This can be used to receive membership updates without exposing a grpc service to
@@ -314,6 +939,10 @@ impl Mesh<Server> {
 
     /// Consume this [Mesh], creating a future that will run on a tokio executor.
     ///
+    /// `addr` identifies the local node in the mesh unless [Mesh::advertise_addr] was set, so
+    /// it can't have port 0 (the mesh exits with [Error::EphemeralPort]). To bind to an
+    /// ephemeral port, use [serve_with_incoming][Self::serve_with_incoming].
+    ///
     /// Resolves once the mesh has exited.
     #[inline]
     pub async fn serve(self, addr: SocketAddr) -> Result {
@@ -322,33 +951,76 @@ impl Mesh<Server> {
 
     /// Consume this [Mesh], creating a future that will run on a tokio executor.
     ///
-    /// Shutdown will be initiated when `signal` resolves.
+    /// Shutdown will be initiated when `signal` resolves. Before the server stops, the local
+    /// node will gracefully leave the mesh by asking its observers to remove it from the
+    /// configuration, rather than waiting for it to be detected as faulty.
+    ///
+    /// Resolves once the mesh has exited.
+    #[inline]
+    pub async fn serve_with_shutdown<F>(self, addr: SocketAddr, signal: F) -> Result
+    where F: Future<Output = ()> + Send {
+        self.launch(Bind::Addr(addr), signal).1.await
+    }
+
+    /// Consume this [Mesh], creating a future that will run on a tokio executor and accept
+    /// connections from a `listener` that is already bound.
+    ///
+    /// The address the listener is bound to identifies the local node in the mesh unless
+    /// [Mesh::advertise_addr] was set, so it may be bound to an ephemeral port. Shutdown is
+    /// initiated when `signal` resolves, as with [serve_with_shutdown][Self::serve_with_shutdown].
     ///
     /// Resolves once the mesh has exited.
-    pub async fn serve_with_shutdown<F>(mut self, addr: SocketAddr, signal: F) -> Result
+    #[inline]
+    pub async fn serve_with_incoming<F>(self, listener: TcpListener, signal: F) -> Result
+    where F: Future<Output = ()> + Send {
+        let addr = listener.local_addr()?;
+        self.launch(Bind::Incoming(addr, listener), signal).1.await
+    }
+
+    /// Consume this [Mesh], creating a future that will run on a tokio executor, as well as
+    /// a [MeshHandle] that can be used to inspect and control the local member while that
+    /// future is running.
+    ///
+    /// Shutdown will be initiated when [shutdown][MeshHandle::shutdown] is called on any
+    /// clone of the handle.
+    ///
+    /// The future resolves once the mesh has exited.
+    #[inline]
+    pub fn serve_with_handle(self, addr: SocketAddr) -> (MeshHandle, impl Future<Output = Result>) {
+        self.launch(Bind::Addr(addr), pending())
+    }
+
+    fn launch<F>(self, bind: Bind, signal: F) -> (MeshHandle, impl Future<Output = Result>)
     where F: Future<Output = ()> + Send {
-        let cluster = Arc::new(Cluster::new(self.cfg, addr));
-
-        select! {
-            r = self.svcs.into_iter()
-                    .map(|s| s.accept(cluster.subscribe()))
-                    .collect::<FuturesUnordered<_>>()
-                    .for_each(|_| async {})
-                    .then(|_| pending()) => r,
-
-            r = Arc::clone(&cluster)
-                    .detect_faults(cluster.subscribe())
-                    .err_into() => r,
-
-            r = Arc::clone(&cluster)
-                    .handle_parts(cluster.subscribe())
-                    .err_into() => r,
-
-            r = self.grpc
-                    .add_service(cluster.into_service())
-                    .serve_with_shutdown(addr, signal)
-                    .err_into() => r,
+        let Mesh { cfg, mut grpc, svcs } = self;
+        let transport = Arc::new(Tonic::new(cfg.client_tls.clone()));
+        let admin = cfg.admin;
+        let local_addr = cfg.advertise.unwrap_or_else(|| bind.addr());
+        let cluster = Arc::new(Cluster::new(cfg, local_addr, transport));
+        let handle = MeshHandle::new(Arc::clone(&cluster));
+
+        let router = grpc
+            .add_service(Arc::clone(&cluster).into_service())
+            .add_optional_service(guard!(admin, Arc::clone(&cluster).into_admin_service()));
+        let signal = handle.shutdown_signal(signal);
+
+        let server = match bind {
+            Bind::Addr(addr) => router.serve_with_shutdown(addr, signal).left_future(),
+            Bind::Incoming(_, listener) => router
+                .serve_with_incoming_shutdown(incoming(listener), signal)
+                .right_future(),
         }
+        .err_into();
+
+        let mesh = run(cluster, svcs, server);
+        let mesh = async move {
+            if local_addr.port() == 0 {
+                return Err(Error::EphemeralPort(local_addr));
+            }
+            mesh.await
+        };
+
+        (handle, mesh)
     }
 }
 
@@ -384,30 +1056,199 @@ where
         self.serve_with_shutdown(addr, pending()).await
     }
 
+    #[inline]
     pub async fn serve_with_shutdown<F>(self, addr: SocketAddr, signal: F) -> Result
     where F: Future<Output = ()> + Send {
-        let cluster = Arc::new(Cluster::new(self.cfg, addr));
-
-        select! {
-            r = self.svcs.into_iter()
-                    .map(|s| s.accept(cluster.subscribe()))
-                    .collect::<FuturesUnordered<_>>()
-                    .for_each(|_| async {})
-                    .then(|_| pending()) => r,
-
-            r = Arc::clone(&cluster)
-                    .detect_faults(cluster.subscribe())
-                    .err_into() => r,
-
-            r = Arc::clone(&cluster)
-                    .handle_parts(cluster.subscribe())
-                    .err_into() => r,
-
-            r = self.grpc
-                    .add_service(cluster.into_service())
-                    .serve_with_shutdown(addr, signal)
-                    .err_into() => r,
+        self.launch(Bind::Addr(addr), signal).1.await
+    }
+
+    #[inline]
+    pub async fn serve_with_incoming<F>(self, listener: TcpListener, signal: F) -> Result
+    where F: Future<Output = ()> + Send {
+        let addr = listener.local_addr()?;
+        self.launch(Bind::Incoming(addr, listener), signal).1.await
+    }
+
+    #[inline]
+    pub fn serve_with_handle(self, addr: SocketAddr) -> (MeshHandle, impl Future<Output = Result>) {
+        self.launch(Bind::Addr(addr), pending())
+    }
+
+    fn launch<F>(self, bind: Bind, signal: F) -> (MeshHandle, impl Future<Output = Result>)
+    where F: Future<Output = ()> + Send {
+        let Mesh { cfg, grpc, svcs } = self;
+        let transport = Arc::new(Tonic::new(cfg.client_tls.clone()));
+        let admin = cfg.admin;
+        let local_addr = cfg.advertise.unwrap_or_else(|| bind.addr());
+        let cluster = Arc::new(Cluster::new(cfg, local_addr, transport));
+        let handle = MeshHandle::new(Arc::clone(&cluster));
+
+        let router = grpc
+            .add_service(Arc::clone(&cluster).into_service())
+            .add_optional_service(guard!(admin, Arc::clone(&cluster).into_admin_service()));
+        let signal = handle.shutdown_signal(signal);
+
+        let server = match bind {
+            Bind::Addr(addr) => router.serve_with_shutdown(addr, signal).left_future(),
+            Bind::Incoming(_, listener) => router
+                .serve_with_incoming_shutdown(incoming(listener), signal)
+                .right_future(),
         }
+        .err_into();
+
+        let mesh = run(cluster, svcs, server);
+        let mesh = async move {
+            if local_addr.port() == 0 {
+                return Err(Error::EphemeralPort(local_addr));
+            }
+            mesh.await
+        };
+
+        (handle, mesh)
+    }
+}
+
+/// Where the grpc server of a mesh accepts connections.
+enum Bind {
+    /// Bind a new listener to an address.
+    Addr(SocketAddr),
+    /// Accept connections from a listener bound to an address.
+    Incoming(SocketAddr, TcpListener),
+}
+
+impl Bind {
+    /// Returns the address connections are accepted on.
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Bind::Addr(addr) | Bind::Incoming(addr, _) => *addr,
+        }
+    }
+}
+
+/// Returns a stream of the connections accepted by `listener`.
+fn incoming(listener: TcpListener) -> impl Stream<Item = io::Result<TcpStream>> {
+    stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(conn, _)| conn);
+        Some((conn, listener))
+    })
+}
+
+/// Run the membership protocol and all [MeshService]s for `cluster` until `server` resolves,
+/// or until any of them fail.
+async fn run<S>(cluster: Arc<Cluster>, svcs: Vec<Box<dyn MeshService>>, server: S) -> Result
+where S: Future<Output = Result> {
+    select! {
+        r = svcs.into_iter()
+                .map(|s| s.accept(cluster.subscribe()))
+                .collect::<FuturesUnordered<_>>()
+                .for_each(|_| async {})
+                .then(|_| pending()) => r,
+
+        r = Arc::clone(&cluster)
+                .detect_faults(cluster.subscribe())
+                .err_into() => r,
+
+        r = Arc::clone(&cluster)
+                .handle_parts(cluster.subscribe())
+                .err_into() => r,
+
+        r = Arc::clone(&cluster)
+                .persist_cuts(cluster.subscribe())
+                .err_into() => r,
+
+        r = Arc::clone(&cluster)
+                .merge_partitions(cluster.subscribe())
+                .err_into() => r,
+
+        r = Arc::clone(&cluster)
+                .admit_requests()
+                .err_into() => r,
+
+        r = server => r,
+    }
+}
+
+/// A handle to a running [Mesh], which can be used to inspect and control the local member.
+///
+/// Cloning this is cheap, and all clones refer to the same member.
+#[derive(Clone)]
+pub struct MeshHandle {
+    cluster: Arc<Cluster>,
+    shutdown: Arc<Notify>,
+}
+
+impl MeshHandle {
+    fn new(cluster: Arc<Cluster>) -> Self {
+        let shutdown = Arc::new(Notify::new());
+        Self { cluster, shutdown }
+    }
+
+    /// Returns a future that resolves after `signal` resolves (or shutdown is initiated from
+    /// the handle), and the local node has gracefully left the mesh.
+    fn shutdown_signal<F>(&self, signal: F) -> impl Future<Output = ()>
+    where F: Future<Output = ()> {
+        let leaver = self.clone();
+
+        async move {
+            select! {
+                _ = signal => {}
+                _ = leaver.shutdown.notified() => {}
+            }
+            leaver.leave().await;
+        }
+    }
+
+    /// Returns the most recently accepted view-change proposal, or `None` if the local
+    /// node hasn't joined a configuration yet.
+    pub async fn current_cut(&self) -> Option<MultiNodeCut> {
+        self.cluster.last_cut().await
+    }
+
+    /// Subscribe to accepted view-change proposals.
+    pub fn subscribe(&self) -> Subscription {
+        self.cluster.subscribe()
+    }
+
+    /// Watch the subjects that the local node's fault detector currently suspects, which are
+    /// those whose last probe failed.
+    ///
+    /// Unlike the cuts received from [MeshHandle::subscribe], suspicions aren't agreed upon
+    /// by the rest of the mesh; a suspected subject may never be removed from the
+    /// configuration.
+    pub fn suspicions(&self) -> watch::Receiver<Arc<[Suspicion]>> {
+        self.cluster.suspicions()
+    }
+
+    /// Returns the address the local node is reachable at, which is the address it was
+    /// served on unless [Mesh::advertise_addr] was set.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.cluster.local_addr()
+    }
+
+    /// Gracefully leave the mesh, without shutting down the server.
+    ///
+    /// Resolves once the rest of the mesh has removed the local node from its configuration
+    /// (or has given up trying to). The local node will not attempt to rejoin afterwards.
+    pub async fn leave(&self) {
+        self.cluster.leave().await
+    }
+
+    /// Update the local node's metadata by applying `f` to it. The change is distributed to
+    /// other members through the same consensus path as joins and departures, after which
+    /// it will be reflected in [updated][MultiNodeCut::updated].
+    ///
+    /// Resolves once every one of the local node's observers has answered (or timed out),
+    /// and succeeds if at least one of them accepted the update. If none do, the updated
+    /// metadata is still used if the local node rejoins the mesh.
+    pub async fn update_metadata<F>(&self, f: F) -> result::Result<(), Status>
+    where F: FnOnce(&mut HashMap<String, Vec<u8>>) {
+        self.cluster.update_metadata(f).await
+    }
+
+    /// Initiate shutdown of the mesh. The local node will gracefully leave before the server
+    /// exits.
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
     }
 }
 
@@ -472,6 +1313,7 @@ where S: Service<HttpRequest<Body>, Response = HttpResponse<BoxBody>>
 ///             // handle membership change
 ///             let _ = cut.members();
 ///             let _ = cut.joined();
+///             let _ = cut.updated();
 ///             let _ = cut.kicked();
 ///         }
 ///     }
@@ -0,0 +1,64 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The source of randomness used by the membership protocol.
+//!
+//! This defers to [thread_rng], unless the current thread has been seeded (which only the
+//! simulator does), in which case every random choice made on it is reproducible.
+use rand::{rngs::StdRng, thread_rng, Error, RngCore};
+use std::cell::RefCell;
+
+thread_local! {
+    // const initializers would need rust 1.59.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static SEEDED: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+/// Seed the random number generator for the current thread, or restore the default if `seed`
+/// is `None`.
+#[cfg(feature = "sim")]
+pub(crate) fn seed(seed: Option<u64>) {
+    use rand::SeedableRng;
+
+    SEEDED.with(|s| *s.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+/// Returns a handle to the random number generator for the current thread.
+#[inline]
+pub(crate) fn local_rng() -> LocalRng {
+    LocalRng
+}
+
+/// A handle to the random number generator for the current thread.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct LocalRng;
+
+impl LocalRng {
+    fn with<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        SEEDED.with(|s| match s.borrow_mut().as_mut() {
+            Some(rng) => f(rng),
+            None => f(&mut thread_rng()),
+        })
+    }
+}
+
+impl RngCore for LocalRng {
+    fn next_u32(&mut self) -> u32 {
+        Self::with(|r| r.next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        Self::with(|r| r.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Self::with(|r| r.fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        Self::with(|r| r.try_fill_bytes(dest))
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! Deterministic simulation of meshes.
+//!
+//! A [Sim] runs a number of members on a [MemNetwork], driven by a single-threaded runtime
+//! with paused time. Timeouts elapse instantly as soon as every member is idle, so scripts
+//! that span minutes of protocol time complete in milliseconds.
+//!
+//! All randomness used by the membership protocol (paxos round delays, node and broadcast
+//! ids, seed shuffling, member selection, and dropped messages) is drawn from a generator
+//! seeded by the simulation, and broadcast timestamps are read from the virtual clock, so a
+//! failing script can be replayed from its seed. Scheduling
+//! decisions made by tokio itself, such as which branch of a `select!` is polled first,
+//! are not covered by the seed.
+//!
+//! After every [Step], the simulator checks that no two members have accepted different
+//! memberships under the same configuration id, and that no member has exited. Every
+//! view-change accepted by a member is recorded as an [Event], and the resulting trace can
+//! be retrieved with [Sim::run_traced].
+//!
+//! ```
+//! use blip::sim::{Sim, Step::*};
+//! use std::time::Duration;
+//!
+//! let secs = Duration::from_secs;
+//!
+//! Sim::new(7, 3)
+//!     .run(&[
+//!         Start(0),
+//!         Start(1),
+//!         Start(2),
+//!         Converge(vec![0, 1, 2], secs(60)),
+//!         Partition(vec![0, 1], vec![2]),
+//!         Converge(vec![0, 1], secs(60)),
+//!     ])
+//!     .unwrap();
+//! ```
+use super::{
+    cluster::transport::MemNetwork,
+    clock,
+    overlay::{self, Mesh},
+    rng, MeshHandle, MeshService, Subscription,
+};
+use futures::future::FutureExt;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    runtime,
+    task::{self, JoinHandle},
+    time::{sleep, Instant},
+};
+use tonic::transport::Server;
+
+/// The interval at which invariants are checked while waiting for convergence.
+const TICK: Duration = Duration::from_millis(100);
+
+/// A scripted event in a simulation. Members are referred to by their index, which ranges
+/// from 0 to the number of members in the simulation.
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// Start a member. It will join the mesh via any other member, or bootstrap a new mesh
+    /// if it is member 0.
+    Start(usize),
+    /// Crash a member, dropping all of its state. Any tasks it spawned that are still running
+    /// can no longer exchange messages with other members. It can be started again later.
+    Crash(usize),
+    /// Partition the network between two groups of members.
+    Partition(Vec<usize>, Vec<usize>),
+    /// Remove all partitions from the network.
+    Heal,
+    /// Let the simulation run for some amount of virtual time.
+    Sleep(Duration),
+    /// Wait up to some amount of virtual time for exactly the provided members to agree on
+    /// a configuration that includes all of them (and nobody else).
+    Converge(Vec<usize>, Duration),
+}
+
+/// A view-change accepted by a member during a simulation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The amount of virtual time since the start of the simulation.
+    pub at: Duration,
+    /// The member that accepted the view-change.
+    pub node: usize,
+    /// The id of the accepted configuration.
+    pub conf_id: u64,
+    /// The members of the accepted configuration, in order.
+    pub members: Vec<SocketAddr>,
+}
+
+/// Records the view-changes accepted by a member into a shared trace.
+struct Recorder {
+    node: usize,
+    start: Instant,
+    trace: Arc<Mutex<Vec<Event>>>,
+}
+
+#[crate::async_trait]
+impl MeshService for Recorder {
+    async fn accept(self: Box<Self>, mut cuts: Subscription) {
+        while let Ok(cut) = cuts.recv().await {
+            self.trace.lock().unwrap().push(Event {
+                at: Instant::now() - self.start,
+                node: self.node,
+                conf_id: cut.conf_id(),
+                members: cut.members().iter().map(|m| m.addr()).collect(),
+            });
+        }
+    }
+}
+
+/// An invariant that was violated during a simulation.
+#[derive(Debug, Error)]
+pub enum Violation {
+    /// Two members accepted different memberships under the same configuration id.
+    #[error("step {step}: members {a} and {b} disagree on configuration {conf_id}")]
+    Disagreement {
+        /// The index of the step after which the violation was observed.
+        step: usize,
+        /// The configuration id both members accepted.
+        conf_id: u64,
+        /// One of the members.
+        a: usize,
+        /// The other member.
+        b: usize,
+    },
+
+    /// A set of members failed to converge on a configuration.
+    #[error("step {step}: members did not converge within {within:?}")]
+    Diverged {
+        /// The index of the converge step.
+        step: usize,
+        /// The amount of virtual time that was waited.
+        within: Duration,
+    },
+
+    /// A member exited.
+    #[error("step {step}: member {node} exited: {reason}")]
+    Exited {
+        /// The index of the step after which the violation was observed.
+        step: usize,
+        /// The member that exited.
+        node: usize,
+        /// Why it exited.
+        reason: String,
+    },
+}
+
+/// A deterministic simulation of a mesh.
+pub struct Sim {
+    seed: u64,
+    addrs: Vec<SocketAddr>,
+    mesh: Box<dyn Fn() -> Mesh<Server>>,
+}
+
+struct Node {
+    handle: MeshHandle,
+    task: JoinHandle<overlay::Result>,
+}
+
+impl Sim {
+    /// Create a simulation of up to `nodes` members, with randomness seeded by `seed`.
+    ///
+    /// Members are configured with [Mesh::low_latency] by default.
+    pub fn new(seed: u64, nodes: usize) -> Self {
+        let addrs = (0..nodes as u32)
+            .map(|i| (Ipv4Addr::from(0x0a00_0001 + i), 10000).into())
+            .collect();
+
+        Self {
+            seed,
+            addrs,
+            mesh: Box::new(Mesh::low_latency),
+        }
+    }
+
+    /// Set the function used to configure each member as it is started. Seeds are added
+    /// by the simulator.
+    pub fn mesh<F: Fn() -> Mesh<Server> + 'static>(mut self, f: F) -> Self {
+        self.mesh = Box::new(f);
+        self
+    }
+
+    /// Returns the simulated address of a member.
+    ///
+    /// # Panics
+    /// Panics if `node` is out of range.
+    pub fn addr(&self, node: usize) -> SocketAddr {
+        self.addrs[node]
+    }
+
+    /// Run `script` to completion, checking invariants after every step.
+    ///
+    /// # Panics
+    /// Panics if a step refers to a member that is out of range.
+    pub fn run(&self, script: &[Step]) -> Result<(), Violation> {
+        self.run_traced(script).0
+    }
+
+    /// Run `script` to completion like [run][Self::run], additionally returning every
+    /// view-change accepted by a member, ordered by the time they were accepted and then by
+    /// member.
+    ///
+    /// Running the same script with the same seed produces the same trace.
+    ///
+    /// # Panics
+    /// Panics if a step refers to a member that is out of range.
+    pub fn run_traced(&self, script: &[Step]) -> (Result<(), Violation>, Vec<Event>) {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let rt = runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .expect("failed to build simulation runtime");
+
+        rng::seed(Some(self.seed));
+        let res = rt.block_on(async {
+            clock::simulate(true);
+            self.execute(script, &trace).await
+        });
+        drop(rt);
+        clock::simulate(false);
+        rng::seed(None);
+
+        // members accept view-changes concurrently, so order those accepted at the same time
+        // by member rather than by which happened to be scheduled first.
+        let mut trace = trace.lock().unwrap().split_off(0);
+        trace.sort_by_key(|e| (e.at, e.node));
+        (res, trace)
+    }
+
+    async fn execute(
+        &self,
+        script: &[Step],
+        trace: &Arc<Mutex<Vec<Event>>>,
+    ) -> Result<(), Violation> {
+        let net = MemNetwork::new();
+        let mut nodes = BTreeMap::new();
+        let start = Instant::now();
+
+        for (step, s) in script.iter().enumerate() {
+            match s {
+                Step::Start(i) => {
+                    let seeds = (self.addrs.iter())
+                        .filter(|a| **a != self.addrs[*i])
+                        .map(|a| (*a, false));
+
+                    let mut mesh = (self.mesh)().add_mesh_service(Recorder {
+                        node: *i,
+                        start,
+                        trace: Arc::clone(trace),
+                    });
+                    if *i != 0 {
+                        mesh = mesh.join_seeds(seeds);
+                    }
+
+                    let (handle, srv) = mesh.serve_in_memory(&net, self.addrs[*i]);
+                    let task = task::spawn(srv);
+                    nodes.insert(*i, Node { handle, task });
+                }
+
+                Step::Crash(i) => {
+                    if let Some(node) = nodes.remove(i) {
+                        node.task.abort();
+                        net.deregister(self.addrs[*i]);
+                    }
+                }
+
+                Step::Partition(a, b) => {
+                    let a: Vec<_> = a.iter().map(|i| self.addrs[*i]).collect();
+                    let b: Vec<_> = b.iter().map(|i| self.addrs[*i]).collect();
+                    net.partition(&a, &b);
+                }
+
+                Step::Heal => net.heal(),
+
+                Step::Sleep(d) => sleep(*d).await,
+
+                Step::Converge(members, within) => {
+                    let deadline = Instant::now() + *within;
+
+                    while !self.converged(&nodes, members).await {
+                        if Instant::now() >= deadline {
+                            let within = *within;
+                            return Err(Violation::Diverged { step, within });
+                        }
+
+                        sleep(TICK).await;
+                        check(step, &mut nodes).await?;
+                    }
+                }
+            }
+
+            task::yield_now().await;
+            check(step, &mut nodes).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether exactly `members` agree on a configuration of `members`.
+    async fn converged(&self, nodes: &BTreeMap<usize, Node>, members: &[usize]) -> bool {
+        let expect: BTreeSet<_> = members.iter().map(|i| self.addrs[*i]).collect();
+        let mut conf_id = None;
+
+        for i in members {
+            let cut = match nodes.get(i) {
+                Some(node) => node.handle.current_cut().await,
+                None => return false,
+            };
+
+            let cut = match cut {
+                Some(cut) if !cut.is_degraded() => cut,
+                _ => return false,
+            };
+
+            if *conf_id.get_or_insert(cut.conf_id()) != cut.conf_id() {
+                return false;
+            }
+
+            if cut.members().iter().map(|m| m.addr()).collect::<BTreeSet<_>>() != expect {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Check that no member has exited, and that members which accepted the same configuration
+/// id agree on its membership.
+async fn check(step: usize, nodes: &mut BTreeMap<usize, Node>) -> Result<(), Violation> {
+    let mut seen = BTreeMap::new();
+
+    for (i, node) in nodes.iter_mut() {
+        if let Some(res) = (&mut node.task).now_or_never() {
+            let reason = match res {
+                Ok(Ok(())) => "shut down".to_owned(),
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            return Err(Violation::Exited {
+                step,
+                node: *i,
+                reason,
+            });
+        }
+
+        let cut = match node.handle.current_cut().await {
+            Some(cut) => cut,
+            None => continue,
+        };
+
+        let members: BTreeSet<_> = cut.members().iter().map(|m| m.addr()).collect();
+
+        match seen.get(&cut.conf_id()) {
+            Some((j, other)) if *other != members => {
+                return Err(Violation::Disagreement {
+                    step,
+                    conf_id: cut.conf_id(),
+                    a: *j,
+                    b: *i,
+                });
+            }
+            Some(_) => {}
+            None => {
+                seen.insert(cut.conf_id(), (*i, members));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -8,11 +8,33 @@
 
 mod shared;
 
-use blip::Mesh;
-use futures::future::{join, join3, FutureExt};
+use blip::{
+    cluster::{
+        admin::{
+            self,
+            proto::{admin_client::AdminClient, InspectReq, LeaveReq, MembersReq, WatchReq},
+        },
+        admission::Joiner,
+        transport::MemNetwork,
+        JoinError,
+    },
+    overlay::{
+        CutDetectorConfig, CutDetectorConfigError, Error, ProtocolTimings, ProtocolTimingsError,
+    },
+    Mesh,
+};
+use futures::future::{join, join3, join_all, pending, FutureExt};
 use shared::init_logger;
-use shared::{addr_in, cfg_handle, subnet};
-use tokio::{select, task};
+use shared::{addr_in, cfg_handle, spawn_in_memory, spawn_mesh, subnet};
+use std::{env, fs, net::SocketAddr, process, time::Duration};
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::oneshot,
+    task,
+    time::{sleep, timeout},
+};
+use tonic::{Code, Request};
 
 /// Tests that a single node can bootstrap a configuration without any other nodes.
 #[tokio::test]
@@ -30,6 +52,32 @@ async fn single_node_cluster_bootstrap() {
     }
 }
 
+/// Tests that a handle to a running mesh reflects its membership state, and can be used to
+/// shut it down.
+#[tokio::test]
+async fn single_node_cluster_handle() {
+    init_logger();
+    let addr = addr_in(subnet(), 1);
+
+    let (handle, srv) = Mesh::low_latency().serve_with_handle(addr);
+    let mut srv = srv.boxed();
+    let mut cuts = handle.subscribe();
+
+    assert_eq!(addr, handle.local_addr());
+
+    let cut = select! {
+        e = &mut srv => panic!("mesh exited with: {:?}", e),
+        cut = cuts.recv() => cut.unwrap(),
+    };
+
+    let current = handle.current_cut().await.unwrap();
+    assert_eq!(cut.conf_id(), current.conf_id());
+    assert_eq!(addr, current.members()[0].addr());
+
+    handle.shutdown();
+    srv.await.unwrap();
+}
+
 /// Tests that three nodes can converge on a single configuration that includes all of them.
 #[tokio::test]
 async fn three_node_cluster_bootstrap() {
@@ -65,6 +113,869 @@ async fn three_node_cluster_bootstrap() {
     }
 }
 
+/// Tests that a node served on an unspecified address can join the mesh by advertising an
+/// address that other members can reach it at.
+#[tokio::test]
+async fn two_node_cluster_advertise_addr() {
+    init_logger();
+    let net = subnet();
+
+    let (mut h1, hs1) = cfg_handle();
+    let s1 = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .serve(addr_in(net, 1));
+
+    // the port is unique to this test, as we listen on every interface.
+    let port = 20000 + net as u16;
+    let advertised = SocketAddr::new(addr_in(net, 2).ip(), port);
+
+    let (mut h2, hs2) = cfg_handle();
+    let s2 = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .advertise_addr(advertised)
+        .join_seed(addr_in(net, 1), false)
+        .serve(([0, 0, 0, 0], port).into());
+
+    select! {
+        e = s1 => panic!("s1 exited with: {:?}", e),
+        e = s2 => panic!("s2 exited with: {:?}", e),
+
+        (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+            assert!(c1.lookup(advertised).is_some());
+            assert_eq!(advertised, c2.local_addr());
+        }
+    }
+}
+
+/// Tests that a node served from a listener bound to port 0 is identified by the port it
+/// was assigned.
+#[tokio::test]
+async fn two_node_cluster_incoming_ephemeral_port() {
+    init_logger();
+    let net = subnet();
+
+    let (mut h1, hs1) = cfg_handle();
+    let s1 = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .serve(addr_in(net, 1));
+
+    let listener = TcpListener::bind(SocketAddr::new(addr_in(net, 2).ip(), 0))
+        .await
+        .unwrap();
+    let bound = listener.local_addr().unwrap();
+    assert_ne!(0, bound.port());
+
+    let (mut h2, hs2) = cfg_handle();
+    let s2 = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .join_seed(addr_in(net, 1), false)
+        .serve_with_incoming(listener, pending());
+
+    select! {
+        e = s1 => panic!("s1 exited with: {:?}", e),
+        e = s2 => panic!("s2 exited with: {:?}", e),
+
+        (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+            assert!(c1.lookup(bound).is_some());
+            assert_eq!(bound, c2.local_addr());
+        }
+    }
+}
+
+/// Tests that a node served on port 0 without a listener exits rather than identifying
+/// itself by a port nobody can connect to.
+#[tokio::test]
+async fn single_node_cluster_rejects_ephemeral_port() {
+    init_logger();
+    let addr = SocketAddr::new(addr_in(subnet(), 1).ip(), 0);
+
+    match Mesh::low_latency().serve(addr).await {
+        Err(Error::EphemeralPort(a)) => assert_eq!(addr, a),
+        r => panic!("expected an ephemeral port error, got: {:?}", r),
+    }
+}
+
+/// Tests that nodes started without any seeds form a single cluster if they expect each
+/// other.
+#[tokio::test]
+async fn three_node_cluster_bootstrap_expect() {
+    init_logger();
+    let net = subnet();
+    let peers: Vec<_> = (1..=3).map(|i| (addr_in(net, i), false)).collect();
+
+    let (mut h1, hs1) = cfg_handle();
+    let s1 = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .bootstrap_expect(3, peers.clone())
+        .serve(addr_in(net, 1));
+
+    let (mut h2, hs2) = cfg_handle();
+    let s2 = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .bootstrap_expect(3, peers.clone())
+        .serve(addr_in(net, 2));
+
+    let (mut h3, hs3) = cfg_handle();
+    let s3 = Mesh::low_latency()
+        .add_mesh_service(hs3)
+        .bootstrap_expect(3, peers)
+        .serve(addr_in(net, 3));
+
+    select! {
+        e = s1 => panic!("s1 exited with: {:?}", e),
+        e = s2 => panic!("s2 exited with: {:?}", e),
+        e = s3 => panic!("s3 exited with: {:?}", e),
+
+        (c1, c2, c3) = join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+            assert!(c2.conf_id() == c3.conf_id());
+        }
+    }
+}
+
+/// Tests that nodes started without any seeds don't bootstrap a cluster until every one
+/// of them has discovered the same peers, even if enough of them are reachable.
+#[tokio::test]
+async fn three_node_cluster_bootstrap_expect_partial_view() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=3).map(|host| addr_in(subnet, host)).collect();
+    let peers: Vec<_> = addrs.iter().map(|addr| (*addr, false)).collect();
+    let mesh = || {
+        Mesh::low_latency()
+            .timings(ProtocolTimings::test())
+            .bootstrap_expect(2, peers.clone())
+    };
+
+    // s1 and s2 can each reach 2 of the 3 nodes, but not each other.
+    net.partition(&addrs[..1], &addrs[1..2]);
+
+    let (_, mut h1) = spawn_in_memory(mesh(), &net, addrs[0]);
+    let (_, mut h2) = spawn_in_memory(mesh(), &net, addrs[1]);
+    let (_, mut h3) = spawn_in_memory(mesh(), &net, addrs[2]);
+
+    let bootstrapped = join3(h1.cfg_change(1), h2.cfg_change(1), h3.cfg_change(1));
+    assert!(timeout(Duration::from_secs(2), bootstrapped).await.is_err());
+
+    net.heal();
+
+    let formed = join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3));
+    let (c1, c2, c3) = timeout(Duration::from_secs(20), formed).await.unwrap();
+    assert!(c1.conf_id() == c2.conf_id());
+    assert!(c2.conf_id() == c3.conf_id());
+}
+
+/// Tests that a node which bootstrapped a cluster of its own is merged into a larger one
+/// that it can reach.
+#[tokio::test]
+async fn three_node_cluster_merge_partitions() {
+    init_logger();
+    let net = subnet();
+
+    let (mut h1, hs1) = cfg_handle();
+    let mut s1 = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .serve(addr_in(net, 1))
+        .boxed();
+
+    let (mut h2, hs2) = cfg_handle();
+    let mut s2 = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 2))
+        .boxed();
+
+    // wait for s1/s2 to form the larger cluster
+    select! {
+        e = &mut s1 => panic!("s1 exited with: {:?}", e),
+        e = &mut s2 => panic!("s2 exited with: {:?}", e),
+
+        (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+        }
+    }
+
+    // s3 has no seeds, so it bootstraps a cluster of its own before finding s1.
+    let (mut h3, hs3) = cfg_handle();
+    let s3 = Mesh::low_latency()
+        .add_mesh_service(hs3)
+        .merge_partitions(Duration::from_millis(200), vec![(addr_in(net, 1), false)])
+        .serve(addr_in(net, 3));
+
+    select! {
+        e = &mut s1 => panic!("s1 exited with: {:?}", e),
+        e = &mut s2 => panic!("s2 exited with: {:?}", e),
+        e = s3 => panic!("s3 exited with: {:?}", e),
+
+        (c1, c2, c3) = join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+            assert!(c2.conf_id() == c3.conf_id());
+            assert!(c3.is_merged());
+        }
+    }
+}
+
+/// Tests that every member of a smaller configuration that formed apart from a larger one
+/// is merged into the larger one once they can reach each other.
+#[tokio::test]
+async fn five_node_cluster_merge_split_brain() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=5).map(|host| addr_in(subnet, host)).collect();
+    let peers: Vec<_> = addrs.iter().map(|addr| (*addr, false)).collect();
+    let mesh = || {
+        Mesh::low_latency()
+            .timings(ProtocolTimings::test())
+            .merge_partitions(Duration::from_millis(200), peers.clone())
+    };
+
+    // s1/s2 and s3/s4/s5 each form a configuration of their own.
+    net.partition(&addrs[..2], &addrs[2..]);
+
+    let (_, mut h1) = spawn_in_memory(mesh(), &net, addrs[0]);
+    let (_, mut h2) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[1]);
+    let (_, mut h3) = spawn_in_memory(mesh(), &net, addrs[2]);
+    let (_, mut h4) = spawn_in_memory(mesh().join_seed(addrs[2], false), &net, addrs[3]);
+    let (_, mut h5) = spawn_in_memory(mesh().join_seed(addrs[2], false), &net, addrs[4]);
+
+    let minority = join(h1.cfg_change(2), h2.cfg_change(2));
+    let majority = join3(h3.cfg_change(3), h4.cfg_change(3), h5.cfg_change(3));
+    let ((c1, _), (c3, _, _)) = timeout(Duration::from_secs(20), join(minority, majority))
+        .await
+        .unwrap();
+    assert!(c1.conf_id() != c3.conf_id());
+
+    net.heal();
+
+    let merged = join_all(vec![
+        h1.cfg_change(5).boxed(),
+        h2.cfg_change(5).boxed(),
+        h3.cfg_change(5).boxed(),
+        h4.cfg_change(5).boxed(),
+        h5.cfg_change(5).boxed(),
+    ]);
+    let cuts = timeout(Duration::from_secs(30), merged).await.unwrap();
+    assert!(cuts.iter().all(|c| c.conf_id() == cuts[0].conf_id()));
+}
+
+/// Tests that a node rotates through its seeds if some of them are unreachable.
+#[tokio::test]
+async fn two_node_cluster_seed_rotation() {
+    init_logger();
+    let net = subnet();
+
+    let (mut h1, hs1) = cfg_handle();
+    let s1 = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .serve(addr_in(net, 1));
+
+    // nothing is listening on hosts 8 or 9, so joins via those seeds will fail.
+    let (mut h2, hs2) = cfg_handle();
+    let s2 = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .join_seeds(vec![
+            (addr_in(net, 8), false),
+            (addr_in(net, 9), false),
+            (addr_in(net, 1), false),
+        ])
+        .shuffle_seeds(true)
+        .serve(addr_in(net, 2));
+
+    select! {
+        e = s1 => panic!("s1 exited with: {:?}", e),
+        e = s2 => panic!("s2 exited with: {:?}", e),
+
+        (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+        }
+    }
+}
+
+/// Tests that a node is ejected by observers placed in another zone when zone-aware
+/// placement is enabled.
+#[tokio::test]
+async fn three_node_cluster_zoned_ejection() {
+    init_logger();
+    let net = subnet();
+    let zoned = |zone: &str| {
+        Mesh::low_latency()
+            .zone_key("zone")
+            .add_metadata(vec![("zone".to_owned(), zone.as_bytes().to_vec())])
+    };
+
+    let (mut h1, hs1) = cfg_handle();
+    let mut s1 = zoned("a")
+        .add_mesh_service(hs1)
+        .serve(addr_in(net, 1))
+        .boxed();
+
+    let (mut h2, hs2) = cfg_handle();
+    let mut s2 = zoned("a")
+        .add_mesh_service(hs2)
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 2))
+        .boxed();
+
+    let (mut h3, hs3) = cfg_handle();
+    let mut s3 = zoned("b")
+        .add_mesh_service(hs3)
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 3))
+        .boxed();
+
+    select! {
+        e = &mut s1 => panic!("s1 exited with: {:?}", e),
+        e = &mut s2 => panic!("s2 exited with: {:?}", e),
+        e = &mut s3 => panic!("s3 exited with: {:?}", e),
+
+        (c1, c2, c3) = join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+            assert!(c2.conf_id() == c3.conf_id());
+        }
+    }
+
+    // every observer of s2 is in zone b, so s3 alone must detect its failure.
+    select! {
+        e = &mut s1 => panic!("s1 exited with: {:?}", e),
+        e = &mut s3 => panic!("s3 exited with: {:?}", e),
+
+        (c1, c3) = join(h1.cfg_change(2), h3.cfg_change(2)) => {
+            assert!(c1.conf_id() == c3.conf_id());
+            assert!(c1.members().iter().all(|m| m.addr() != addr_in(net, 2)));
+        }
+    }
+}
+
+/// Tests that a member whose zone is changed by a metadata update is placed the same way by
+/// members that saw the update, and by a member that joined after it.
+#[tokio::test]
+async fn five_node_cluster_zone_update() {
+    init_logger();
+    let net = subnet();
+    let zoned = |zone: &str| {
+        Mesh::low_latency()
+            .admin(true)
+            .zone_key("zone")
+            .add_metadata(vec![("zone".to_owned(), zone.as_bytes().to_vec())])
+    };
+    let seed = addr_in(net, 1);
+
+    let mut h1 = spawn_mesh(zoned("a"), seed);
+    h1.cfg_change(1).await;
+    let _h2 = spawn_mesh(zoned("a").join_seed(seed, false), addr_in(net, 2));
+    let _h3 = spawn_mesh(zoned("a").join_seed(seed, false), addr_in(net, 3));
+
+    let (mut h4, hs4) = cfg_handle();
+    let (m4, s4) = (zoned("b").add_mesh_service(hs4))
+        .join_seed(seed, false)
+        .serve_with_handle(addr_in(net, 4));
+    task::spawn(s4);
+    join(h1.cfg_change(4), h4.cfg_change(4)).await;
+
+    // s4 is the only member of zone b until it moves into zone a.
+    (m4.update_metadata(|meta| {
+        meta.insert("zone".to_owned(), b"a".to_vec());
+    }))
+    .await
+    .unwrap();
+
+    let moved = async {
+        loop {
+            let cut = h1.cfg_change(4).await;
+            if &*cut[addr_in(net, 4)].metadata()["zone"] == b"a" {
+                break;
+            }
+        }
+    };
+    timeout(Duration::from_secs(10), moved).await.unwrap();
+
+    // s5 places every member from their current metadata when it joins.
+    let mut h5 = spawn_mesh(zoned("a").join_seed(seed, false), addr_in(net, 5));
+    let (c1, c5) = join(h1.cfg_change(5), h5.cfg_change(5)).await;
+    assert!(c1.conf_id() == c5.conf_id());
+
+    let mut states = Vec::new();
+    for host in 1..=5 {
+        let addr = format!("http://{}", addr_in(net, host));
+        let mut c = AdminClient::connect(addr).await.unwrap();
+        states.push(c.inspect(InspectReq {}).await.unwrap().into_inner());
+    }
+    assert!(states.iter().all(|s| s.conf_id == c1.conf_id()));
+
+    // every member's observers must agree with who considers it a subject.
+    for s in states.iter() {
+        let mut observers = s.observers.clone();
+        let mut expected: Vec<_> = (states.iter())
+            .flat_map(|o| o.subjects.iter().filter(|e| **e == s.local).map(move |_| &o.local))
+            .cloned()
+            .collect();
+
+        observers.sort();
+        expected.sort();
+        assert_eq!(expected, observers);
+    }
+}
+
+/// Tests that a member of a three node configuration running on an in-memory network is
+/// ejected once it becomes partitioned from the others.
+#[tokio::test]
+async fn three_node_cluster_in_memory_partition() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=3).map(|host| addr_in(subnet, host)).collect();
+    let mesh = || Mesh::low_latency().timings(ProtocolTimings::test());
+
+    let (_, mut h1) = spawn_in_memory(mesh(), &net, addrs[0]);
+    let (_, mut h2) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[1]);
+    let (_, mut h3) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[2]);
+
+    let (c1, c2, c3) = join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)).await;
+    assert!(c1.conf_id() == c2.conf_id());
+    assert!(c2.conf_id() == c3.conf_id());
+
+    net.partition(&addrs[..2], &addrs[2..]);
+
+    let (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)).await;
+    assert!(c1.conf_id() == c2.conf_id());
+    assert!(c1.members().iter().all(|m| m.addr() != addrs[2]));
+}
+
+/// Tests that a member's fault detector reports a partitioned subject as suspected, and
+/// drops the suspicion once the subject is removed from the configuration.
+#[tokio::test]
+async fn three_node_cluster_in_memory_suspicions() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=3).map(|host| addr_in(subnet, host)).collect();
+    let mesh = || Mesh::low_latency().timings(ProtocolTimings::test());
+
+    let (m1, mut h1) = spawn_in_memory(mesh(), &net, addrs[0]);
+    let (_, mut h2) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[1]);
+    let (_, mut h3) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[2]);
+
+    join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)).await;
+
+    let mut suspicions = m1.suspicions();
+    assert!(suspicions.borrow().is_empty());
+
+    net.partition(&addrs[..2], &addrs[2..]);
+
+    loop {
+        suspicions.changed().await.unwrap();
+        let current = suspicions.borrow().clone();
+        if let Some(s) = current.iter().find(|s| s.addr() == addrs[2]) {
+            assert!(s.strikes() >= 1);
+            assert!(s.last_rtt().is_some());
+            break;
+        }
+    }
+
+    h1.cfg_change(2).await;
+
+    while !suspicions.borrow().is_empty() {
+        suspicions.changed().await.unwrap();
+    }
+}
+
+/// Tests that a member which misses a view-change while partitioned catches up to it once
+/// the partition heals, rather than remaining in a stale configuration.
+#[tokio::test]
+async fn five_node_cluster_in_memory_catch_up() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=5).map(|host| addr_in(subnet, host)).collect();
+
+    // probes are slow enough that the partitioned member isn't ejected, and a single report
+    // is enough for a view-change so that its own reports aren't needed.
+    let cd = CutDetectorConfig {
+        unstable_threshold: 1,
+        stable_threshold: 1,
+        ..CutDetectorConfig::new()
+    };
+    let mesh = || Mesh::new().timings(ProtocolTimings::test()).cd_config(cd);
+
+    let (_, mut h1) = spawn_in_memory(mesh(), &net, addrs[0]);
+    let (_, mut h2) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[1]);
+    let (_, mut h3) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[2]);
+    let (_, mut h4) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[3]);
+
+    let (c1, c2, c3) = join3(h1.cfg_change(4), h2.cfg_change(4), h3.cfg_change(4)).await;
+    let c4 = h4.cfg_change(4).await;
+    assert!(c1.conf_id() == c2.conf_id());
+    assert!(c2.conf_id() == c3.conf_id());
+    assert!(c3.conf_id() == c4.conf_id());
+
+    // s4 misses the broadcasts for s5 joining.
+    net.partition(&addrs[3..4], &[&addrs[..3], &addrs[4..]].concat());
+
+    let (_, mut h5) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[4]);
+
+    let (c1, c5) = join(h1.cfg_change(5), h5.cfg_change(5)).await;
+    assert!(c1.conf_id() == c5.conf_id());
+
+    net.heal();
+
+    let c4 = timeout(Duration::from_secs(10), h4.cfg_change(5))
+        .await
+        .unwrap();
+    assert!(c4.conf_id() == c1.conf_id());
+    assert!(!c4.is_degraded());
+    assert_eq!(1, c4.joined().len());
+    assert_eq!(addrs[4], c4.joined()[0].addr());
+}
+
+/// Tests that a two node configuration which persists its membership can be recovered
+/// after both members restart at once.
+#[tokio::test]
+async fn two_node_cluster_static_recovery() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=2).map(|host| addr_in(subnet, host)).collect();
+    let paths: Vec<_> = (1..=2)
+        .map(|host| env::temp_dir().join(format!("blip-{}-{}-{}", process::id(), subnet, host)))
+        .collect();
+
+    let (mut h1, hs1) = cfg_handle();
+    let (_, s1) = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .persist(&paths[0])
+        .serve_in_memory(&net, addrs[0]);
+    let t1 = task::spawn(s1);
+
+    let (mut h2, hs2) = cfg_handle();
+    let (_, s2) = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .persist(&paths[1])
+        .join_seed(addrs[0], false)
+        .serve_in_memory(&net, addrs[1]);
+    let t2 = task::spawn(s2);
+
+    let (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)).await;
+    assert!(c1.conf_id() == c2.conf_id());
+
+    // give the snapshots a moment to hit the disk, then crash both members
+    sleep(Duration::from_millis(200)).await;
+    t1.abort();
+    t2.abort();
+    sleep(Duration::from_millis(200)).await;
+
+    let (_, mut h1) = spawn_in_memory(
+        Mesh::low_latency().persist(&paths[0]).static_recovery(true),
+        &net,
+        addrs[0],
+    );
+
+    let (_, mut h2) = spawn_in_memory(
+        Mesh::low_latency().persist(&paths[1]).static_recovery(true),
+        &net,
+        addrs[1],
+    );
+
+    let (r1, r2) = join(h1.cfg_change(2), h2.cfg_change(2)).await;
+    assert!(r1.conf_id() == c1.conf_id());
+    assert!(r2.conf_id() == c1.conf_id());
+
+    paths.iter().for_each(|p| fs::remove_file(p).unwrap());
+}
+
+/// Tests that members of a two node configuration record metrics about joining and
+/// probing each other.
+#[tokio::test]
+async fn two_node_cluster_metrics() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=2).map(|host| addr_in(subnet, host)).collect();
+
+    let (mut h1, hs1) = cfg_handle();
+    let m1 = Mesh::low_latency().add_mesh_service(hs1);
+    let metrics1 = m1.metrics();
+    let (_, s1) = m1.serve_in_memory(&net, addrs[0]);
+    task::spawn(s1);
+    h1.cfg_change(1).await;
+
+    let m2 = Mesh::low_latency().join_seed(addrs[0], false);
+    let metrics2 = m2.metrics();
+    let (_, s2) = m2.serve_in_memory(&net, addrs[1]);
+    task::spawn(s2);
+    h1.cfg_change(2).await;
+
+    assert_eq!(1, metrics2.join_latency().count());
+    assert!(metrics1.alerts_received().get() > 0);
+
+    sleep(Duration::from_millis(500)).await;
+    assert!(metrics1.probe_rtt(addrs[1]).unwrap().count() > 0);
+    assert!(metrics1.encode_prometheus().contains("blip_probe_rtt_seconds_count"));
+}
+
+/// Tests that the admin service of a single node configuration reflects its state.
+#[tokio::test]
+async fn single_node_cluster_admin_inspect() {
+    init_logger();
+    let addr = addr_in(subnet(), 1);
+
+    let mut h = spawn_mesh(Mesh::low_latency().admin(true), addr);
+    let cut = h.cfg_change(1).await;
+
+    let mut c = AdminClient::connect(format!("http://{}", addr)).await.unwrap();
+    let state = c.inspect(InspectReq {}).await.unwrap().into_inner();
+
+    assert_eq!(addr.to_string(), state.local);
+    assert_eq!(cut.conf_id(), state.conf_id);
+    assert_eq!(10, state.rings.len());
+    assert!(state.rings.iter().all(|r| r.nodes == [addr.to_string()]));
+    assert!(state.cd_reports.is_empty());
+}
+
+/// Tests that a member of a two node configuration can be asked to leave through the admin
+/// service, and that the other member's view-changes can be watched through it.
+#[tokio::test]
+async fn two_node_cluster_admin_leave() {
+    init_logger();
+    let net = subnet();
+
+    let mut h1 = spawn_mesh(Mesh::low_latency().admin(true), addr_in(net, 1));
+    h1.cfg_change(1).await;
+
+    let s2 = Mesh::low_latency()
+        .admin(true)
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 2));
+    task::spawn(s2);
+    h1.cfg_change(2).await;
+
+    let mut c1 = AdminClient::connect(format!("http://{}", addr_in(net, 1))).await.unwrap();
+    let mut c2 = AdminClient::connect(format!("http://{}", addr_in(net, 2))).await.unwrap();
+
+    let view = c1.members(MembersReq {}).await.unwrap().into_inner();
+    assert_eq!(2, view.members.len());
+
+    let mut views = c1.watch(WatchReq {}).await.unwrap().into_inner();
+    assert_eq!(view.conf_id, views.message().await.unwrap().unwrap().conf_id);
+
+    c2.leave(LeaveReq {}).await.unwrap();
+    h1.cfg_change(1).await;
+
+    let view = views.message().await.unwrap().unwrap();
+    assert_eq!(1, view.members.len());
+    assert_eq!(addr_in(net, 2).to_string(), view.kicked[0].addr);
+}
+
+/// Tests that the admin service of a node configured with a cluster secret only handles
+/// requests that were signed with it.
+#[tokio::test]
+async fn single_node_cluster_admin_secret() {
+    init_logger();
+    let addr = addr_in(subnet(), 1);
+
+    let mut h = spawn_mesh(Mesh::low_latency().admin(true).cluster_secret("hunter2"), addr);
+    h.cfg_change(1).await;
+
+    let mut c = AdminClient::connect(format!("http://{}", addr)).await.unwrap();
+
+    let err = c.inspect(InspectReq {}).await.unwrap_err();
+    assert_eq!(Code::Unauthenticated, err.code());
+
+    let mut req = Request::new(LeaveReq {});
+    admin::sign(b"wrong", &mut req);
+    let err = c.leave(req).await.unwrap_err();
+    assert_eq!(Code::Unauthenticated, err.code());
+
+    let mut req = Request::new(InspectReq {});
+    admin::sign(b"hunter2", &mut req);
+    let state = c.inspect(req).await.unwrap().into_inner();
+    assert_eq!(addr.to_string(), state.local);
+}
+
+/// Tests that a node rejected by the admission hook of its observer is told why, and gives
+/// up on joining.
+#[tokio::test]
+async fn two_node_cluster_admission_rejected() {
+    init_logger();
+    let net = subnet();
+
+    let (mut h1, hs1) = cfg_handle();
+    let s1 = Mesh::low_latency()
+        .add_mesh_service(hs1)
+        .admission(|j: &Joiner| match j.metadata().get("role") {
+            Some(_) => Ok(()),
+            None => Err("missing role".to_owned()),
+        })
+        .serve(addr_in(net, 1));
+    task::spawn(s1);
+    h1.cfg_change(1).await;
+
+    let s2 = Mesh::low_latency()
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 2));
+
+    match timeout(Duration::from_secs(10), s2).await.unwrap() {
+        Err(Error::Join(JoinError::Rejected(reason))) => assert_eq!("missing role", reason),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let mut h3 = spawn_mesh(
+        Mesh::low_latency()
+            .add_metadata(vec![("role".to_owned(), b"db".to_vec())])
+            .join_seed(addr_in(net, 1), false),
+        addr_in(net, 3),
+    );
+
+    let (c1, c3) = join(h1.cfg_change(2), h3.cfg_change(2)).await;
+    assert!(c1.conf_id() == c3.conf_id());
+}
+
+/// Tests that a node configured with a different cluster name can't join the mesh.
+#[tokio::test]
+async fn two_node_cluster_name_mismatch() {
+    init_logger();
+    let net = subnet();
+
+    let mut h1 = spawn_mesh(Mesh::low_latency().cluster_name("prod"), addr_in(net, 1));
+
+    let c1 = h1.cfg_change(1).await;
+    assert_eq!("prod", c1.cluster_name());
+
+    let mut h2 = spawn_mesh(
+        Mesh::low_latency()
+            .cluster_name("staging")
+            .join_seed(addr_in(net, 1), false),
+        addr_in(net, 2),
+    );
+
+    let mut h3 = spawn_mesh(
+        Mesh::low_latency()
+            .cluster_name("prod")
+            .join_seed(addr_in(net, 1), false),
+        addr_in(net, 3),
+    );
+
+    let (c1, c3) = join(h1.cfg_change(2), h3.cfg_change(2)).await;
+    assert!(c1.conf_id() == c3.conf_id());
+    assert!(c1.lookup(addr_in(net, 2)).is_none());
+
+    assert!(timeout(Duration::from_secs(2), h2.cfg_change(2)).await.is_err());
+}
+
+/// Tests that members configured with a secret reject every message from nodes that sign
+/// with a different one.
+#[tokio::test]
+async fn two_node_cluster_secret_mismatch() {
+    init_logger();
+    let net = subnet();
+
+    let mut h1 = spawn_mesh(
+        Mesh::low_latency().cluster_secret("hunter2"),
+        addr_in(net, 1),
+    );
+
+    h1.cfg_change(1).await;
+
+    let mut h2 = spawn_mesh(
+        Mesh::low_latency()
+            .cluster_secret("hunter3")
+            .join_seed(addr_in(net, 1), false),
+        addr_in(net, 2),
+    );
+
+    let mut h3 = spawn_mesh(
+        Mesh::low_latency()
+            .cluster_secret("hunter2")
+            .join_seed(addr_in(net, 1), false),
+        addr_in(net, 3),
+    );
+
+    let (c1, c3) = join(h1.cfg_change(2), h3.cfg_change(2)).await;
+    assert!(c1.conf_id() == c3.conf_id());
+    assert!(c1.lookup(addr_in(net, 2)).is_none());
+
+    assert!(timeout(Duration::from_secs(2), h2.cfg_change(2)).await.is_err());
+}
+
+/// Tests that a node configured with different cut detector thresholds can't join the mesh.
+#[tokio::test]
+async fn two_node_cluster_cd_config_mismatch() {
+    init_logger();
+    let net = subnet();
+
+    let mut h1 = spawn_mesh(Mesh::low_latency(), addr_in(net, 1));
+    h1.cfg_change(1).await;
+
+    let s2 = Mesh::low_latency()
+        .cd_config(CutDetectorConfig::small())
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 2));
+    task::spawn(s2);
+
+    let mut h3 = spawn_mesh(
+        Mesh::low_latency().join_seed(addr_in(net, 1), false),
+        addr_in(net, 3),
+    );
+
+    let (c1, c3) = join(h1.cfg_change(2), h3.cfg_change(2)).await;
+    assert!(c1.conf_id() == c3.conf_id());
+    assert!(c1.members().iter().all(|m| m.addr() != addr_in(net, 2)));
+}
+
+/// Tests that invalid cut detector thresholds are reported rather than accepted.
+#[test]
+fn cd_config_invalid_thresholds() {
+    let cfg = CutDetectorConfig {
+        unstable_threshold: 4,
+        stable_threshold: 12,
+        subjects_per_observer: 10,
+    };
+
+    match Mesh::new().try_cd_config(cfg) {
+        Err(CutDetectorConfigError::StableAboveSubjects { stable, subjects }) => {
+            assert_eq!((12, 10), (stable, subjects))
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("invalid config was accepted"),
+    }
+
+    for cfg in &[
+        CutDetectorConfig::small(),
+        CutDetectorConfig::large(),
+        CutDetectorConfig::high_churn(),
+    ] {
+        assert_eq!(Ok(()), cfg.validate());
+    }
+}
+
+/// Tests that invalid protocol timings are reported rather than accepted.
+#[test]
+fn timings_invalid_durations() {
+    let t = ProtocolTimings {
+        join_retry_min: Duration::from_secs(8),
+        ..ProtocolTimings::lan()
+    };
+
+    match Mesh::new().try_timings(t) {
+        Err(ProtocolTimingsError::JoinRetryMinAboveMax { min, max }) => {
+            assert_eq!((Duration::from_secs(8), Duration::from_secs(4)), (min, max))
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("invalid timings were accepted"),
+    }
+
+    for t in &[
+        ProtocolTimings::lan(),
+        ProtocolTimings::wan(),
+        ProtocolTimings::test(),
+    ] {
+        assert_eq!(Ok(()), t.validate());
+    }
+}
+
 /// Tests that in the event a member of a three node configuration becomes partitioned from
 /// the others, it is ejected from the configuration. Once it comes back online, it should
 /// rejoin the cluster.
@@ -128,6 +1039,66 @@ async fn three_node_cluster_partition_recovery() {
     }
 }
 
+/// Tests that a member of a three node configuration which shuts down gracefully is removed
+/// by the remaining members well before the fault detector would have noticed it.
+#[tokio::test]
+async fn three_node_cluster_graceful_leave() {
+    init_logger();
+    let net = subnet();
+
+    let (mut h1, hs1) = cfg_handle();
+    let mut s1 = Mesh::new()
+        .add_mesh_service(hs1)
+        .serve(addr_in(net, 1))
+        .boxed();
+
+    let (mut h2, hs2) = cfg_handle();
+    let mut s2 = Mesh::new()
+        .add_mesh_service(hs2)
+        .join_seed(addr_in(net, 1), false)
+        .serve(addr_in(net, 2))
+        .boxed();
+
+    let (tx, rx) = oneshot::channel();
+    let (mut h3, hs3) = cfg_handle();
+    let mut s3 = Mesh::new()
+        .add_mesh_service(hs3)
+        .join_seed(addr_in(net, 1), false)
+        .serve_with_shutdown(addr_in(net, 3), rx.map(|_| ()))
+        .boxed();
+
+    // wait for cluster to bootstrap
+    select! {
+        e = &mut s1 => panic!("s1 exited with: {:?}", e),
+        e = &mut s2 => panic!("s2 exited with: {:?}", e),
+        e = &mut s3 => panic!("s3 exited with: {:?}", e),
+
+        (c1, c2, c3) = join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)) => {
+            assert!(c1.conf_id() == c2.conf_id());
+            assert!(c2.conf_id() == c3.conf_id());
+        }
+    }
+
+    tx.send(()).unwrap();
+
+    // the default fault detector takes ~6s to notice a missing member.
+    let left = join(s3, join(h1.cfg_change(2), h2.cfg_change(2)));
+
+    select! {
+        e = &mut s1 => panic!("s1 exited with: {:?}", e),
+        e = &mut s2 => panic!("s2 exited with: {:?}", e),
+
+        r = timeout(Duration::from_secs(3), left) => {
+            let (r3, (c1, c2)) = r.expect("leave timed out");
+            r3.expect("s3 exited with an error");
+
+            assert!(c1.conf_id() == c2.conf_id());
+            assert_eq!(1, c1.kicked().len());
+            assert_eq!(addr_in(net, 3), c1.kicked()[0].addr());
+        }
+    }
+}
+
 /// Tests that both members of a two node configuration agree on each other's metadata.
 #[tokio::test]
 async fn two_node_cluster_metadata_consensus() {
@@ -249,3 +1220,87 @@ async fn three_node_cluster_metadata_rejoin_consensus() {
         }
     }
 }
+
+/// Tests that a metadata update made by one member of a two node configuration is agreed upon
+/// by both members.
+#[tokio::test]
+async fn two_node_cluster_metadata_update() {
+    init_logger();
+    let net = subnet();
+
+    let mut h1 = spawn_mesh(Mesh::low_latency(), addr_in(net, 1));
+
+    let (mut h2, hs2) = cfg_handle();
+    let (handle, s2) = Mesh::low_latency()
+        .add_mesh_service(hs2)
+        .add_metadata(vec![("load".to_owned(), b"low".to_vec())])
+        .join_seed(addr_in(net, 1), false)
+        .serve_with_handle(addr_in(net, 2));
+    task::spawn(s2);
+
+    let (c1, _) = join(h1.cfg_change(2), h2.cfg_change(2)).await;
+    assert_eq!(b"low".as_ref(), &*c1[addr_in(net, 2)].metadata()["load"]);
+    let conf_id = c1.conf_id();
+
+    (handle.update_metadata(|meta| {
+        meta.insert("load".to_owned(), b"high".to_vec());
+    }))
+    .await
+    .unwrap();
+
+    let (c1, c2) = join(h1.cfg_change(2), h2.cfg_change(2)).await;
+
+    assert!(c1.conf_id() == c2.conf_id());
+    assert_ne!(conf_id, c1.conf_id());
+
+    for c in [c1, c2].iter() {
+        assert_eq!(1, c.updated().len());
+        assert_eq!(addr_in(net, 2), c.updated()[0].addr());
+        assert_eq!(b"high".as_ref(), &*c[addr_in(net, 2)].metadata()["load"]);
+    }
+}
+
+/// Tests that members agree on the outcome of concurrent metadata updates from the same
+/// member, even if they receive alerts for them in different orders.
+#[tokio::test]
+async fn three_node_cluster_concurrent_metadata_updates() {
+    init_logger();
+    let net = MemNetwork::new();
+    let subnet = subnet();
+    let addrs: Vec<_> = (1..=3).map(|host| addr_in(subnet, host)).collect();
+    let mesh = || Mesh::low_latency().timings(ProtocolTimings::test());
+
+    let (m1, mut h1) = spawn_in_memory(mesh(), &net, addrs[0]);
+    let (m2, mut h2) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[1]);
+    let (m3, mut h3) = spawn_in_memory(mesh().join_seed(addrs[0], false), &net, addrs[2]);
+
+    join3(h1.cfg_change(3), h2.cfg_change(3), h3.cfg_change(3)).await;
+
+    let update = |load: &'static [u8]| {
+        m3.update_metadata(move |meta| {
+            meta.insert("load".to_owned(), load.to_vec());
+        })
+    };
+    let (r1, r2) = join(update(b"low"), update(b"high")).await;
+    assert!(r1.is_ok() || r2.is_ok());
+
+    let settled = async {
+        loop {
+            let (c1, c2, c3) = join3(m1.current_cut(), m2.current_cut(), m3.current_cut()).await;
+            let (c1, c2, c3) = (c1.unwrap(), c2.unwrap(), c3.unwrap());
+
+            if c1.conf_id() == c2.conf_id()
+                && c2.conf_id() == c3.conf_id()
+                && c1[addrs[2]].metadata().contains_key("load")
+            {
+                return (c1, c2, c3);
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    };
+
+    let (c1, c2, c3) = timeout(Duration::from_secs(10), settled).await.unwrap();
+    let load = &c1[addrs[2]].metadata()["load"];
+    assert_eq!(load, &c2[addrs[2]].metadata()["load"]);
+    assert_eq!(load, &c3[addrs[2]].metadata()["load"]);
+}
@@ -5,16 +5,19 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 //! Shared code referred to by multiple test modules.
-#![allow(unused_attributes)]
+#![allow(dead_code, unused_attributes)]
 #![type_length_limit = "8388608"]
 
-use blip::{MeshService, MultiNodeCut, Subscription};
+use blip::{
+    cluster::transport::MemNetwork, Mesh, MeshHandle, MeshService, MultiNodeCut, Subscription,
+};
 use simplelog::{Config, LevelFilter, TestLogger};
 use std::{
     net::SocketAddr,
     sync::atomic::{AtomicU32, Ordering::Relaxed},
 };
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, task};
+use tonic::transport::Server;
 
 // A quick NOTE about addressing in integration tests: each test should subnet a unique /20
 // from the 127.128/9 loopback block to avoid collisions with other tests or os services.
@@ -24,7 +27,7 @@ use tokio::sync::mpsc;
 pub fn subnet() -> u32 {
     static SUBNET: AtomicU32 = AtomicU32::new(0);
     let s = SUBNET.fetch_add(1, Relaxed);
-    assert!(s <= 2 ^ 11);
+    assert!(s < 1 << 11);
     s
 }
 
@@ -49,6 +52,26 @@ pub fn cfg_handle() -> (CfgHandle, CfgService) {
     (h, s)
 }
 
+/// Serve `mesh` at `addr` in the background, returning a handle to its view-changes.
+pub fn spawn_mesh(mesh: Mesh<Server>, addr: SocketAddr) -> CfgHandle {
+    let (h, hs) = cfg_handle();
+    task::spawn(mesh.add_mesh_service(hs).serve(addr));
+    h
+}
+
+/// Serve `mesh` on the in-memory network `net` at `addr` in the background, returning a
+/// handle to the mesh as well as to its view-changes.
+pub fn spawn_in_memory(
+    mesh: Mesh<Server>,
+    net: &MemNetwork,
+    addr: SocketAddr,
+) -> (MeshHandle, CfgHandle) {
+    let (h, hs) = cfg_handle();
+    let (handle, srv) = mesh.add_mesh_service(hs).serve_in_memory(net, addr);
+    task::spawn(srv);
+    (handle, h)
+}
+
 pub struct CfgService {
     tx: mpsc::Sender<MultiNodeCut>,
 }
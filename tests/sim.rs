@@ -0,0 +1,63 @@
+// Copyright 2020 nytopop (Eric Izoita)
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "sim")]
+
+use blip::sim::{Sim, Step::*};
+use std::time::Duration;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Tests that a simulated mesh survives a crash, a partition, and a restart.
+#[test]
+fn five_node_sim_crash_and_partition() {
+    Sim::new(1, 5)
+        .run(&[
+            Start(0),
+            Start(1),
+            Start(2),
+            Start(3),
+            Start(4),
+            Converge(vec![0, 1, 2, 3, 4], MINUTE),
+            Crash(4),
+            Converge(vec![0, 1, 2, 3], MINUTE),
+            Partition(vec![0, 1, 2], vec![3]),
+            Converge(vec![0, 1, 2], MINUTE),
+            Heal,
+            Start(4),
+            Converge(vec![0, 1, 2, 4], MINUTE),
+        ])
+        .unwrap();
+}
+
+/// Tests that running the same script with the same seed produces the same trace.
+#[test]
+fn five_node_sim_is_reproducible() {
+    let script = [
+        Start(0),
+        Start(1),
+        Start(2),
+        Start(3),
+        Start(4),
+        Converge(vec![0, 1, 2, 3, 4], MINUTE),
+        Crash(4),
+        Converge(vec![0, 1, 2, 3], MINUTE),
+        Partition(vec![0, 1, 2], vec![3]),
+        Converge(vec![0, 1, 2], MINUTE),
+        Heal,
+        Start(4),
+        Converge(vec![0, 1, 2, 4], MINUTE),
+    ];
+
+    let sim = Sim::new(3, 5);
+    let (res, a) = sim.run_traced(&script);
+    res.unwrap();
+    let (res, b) = sim.run_traced(&script);
+    res.unwrap();
+
+    assert!(!a.is_empty());
+    assert_eq!(a, b);
+}